@@ -3,7 +3,7 @@ use bevy_orbits::prelude::*;
 
 #[bevy_main]
 fn main() {
-    App::new().add_plugins((DefaultPlugins, OrbitPlugin)).add_systems(Startup, startup).run();
+    App::new().add_plugins((DefaultPlugins, OrbitPlugin::default())).add_systems(Startup, startup).run();
 }
 
 fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
@@ -27,7 +27,7 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 material: material.clone(),
                 ..default()
             },
-            Mass { mass: 1e12 },
+            Mass::Mass(1e12),
         ))
         .id();
 
@@ -42,6 +42,8 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 semi_major_axis: 4.0,
                 eccentricity: 0.0,
                 argument_of_periapsis: 0.0,
+                inclination: 0.0,
+                longitude_of_ascending_node: 0.0,
                 initial_mean_anomaly: 0.0,
             },
         ))
@@ -1,23 +1,20 @@
 mod utils;
 
-use std::f32::consts::TAU;
-
 use bevy::prelude::*;
 use bevy_egui::egui::Ui;
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use bevy_orbits::math::consts::{PI, TAU};
 use bevy_orbits::prelude::*;
 use format_num::format_num;
 
-use utils::{dejitter_orbit, draw_orbit, MassChanged, OrbitChanged};
+use utils::draw_orbit;
 
 #[bevy_main]
 fn main() {
     App::new()
-        .add_plugins((DefaultPlugins, OrbitPlugin, EguiPlugin))
-        .add_event::<MassChanged>()
-        .add_event::<OrbitChanged>()
+        .add_plugins((DefaultPlugins, OrbitPlugin::default(), EguiPlugin))
         .add_systems(Startup, startup)
-        .add_systems(Update, (ui, dejitter_orbit, draw_orbits).chain())
+        .add_systems(Update, (ui, draw_orbits).chain())
         .run();
 }
 
@@ -39,7 +36,7 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 }),
                 ..default()
             },
-            Mass { mass: 1e12 },
+            Mass::Mass(1e12),
         ))
         .id();
 
@@ -59,9 +56,12 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 semi_major_axis: 4.0,
                 eccentricity: 0.0,
                 argument_of_periapsis: 0.0,
+                inclination: 0.0,
+                longitude_of_ascending_node: 0.0,
                 initial_mean_anomaly: 0.0,
             },
-            Mass { mass: 1e10 },
+            PreserveTrueAnomaly::default(),
+            Mass::Mass(1e10),
         ))
         .set_parent(sun)
         .id();
@@ -82,8 +82,11 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 semi_major_axis: 1.0,
                 eccentricity: 0.0,
                 argument_of_periapsis: 0.0,
+                inclination: 0.0,
+                longitude_of_ascending_node: 0.0,
                 initial_mean_anomaly: 0.0,
             },
+            PreserveTrueAnomaly::default(),
         ))
         .set_parent(earth);
 }
@@ -97,79 +100,86 @@ struct Earth;
 #[derive(Component)]
 struct Moon;
 
+#[allow(clippy::type_complexity)]
 fn ui(
     mut egui_contexts: EguiContexts,
     mut queries: ParamSet<(
-        Query<(Entity, &mut Mass), With<Sun>>,
-        Query<(Entity, &mut Orbit, &mut Mass), With<Earth>>,
-        Query<(Entity, &mut Orbit), With<Moon>>,
+        Query<&mut Mass, With<Sun>>,
+        Query<(&mut Orbit, &mut Mass), With<Earth>>,
+        Query<&mut Orbit, With<Moon>>,
     )>,
-    mut mass_changed: EventWriter<MassChanged>,
-    mut orbit_changed: EventWriter<OrbitChanged>,
+    mut orbital_time: ResMut<OrbitalTime>,
 ) {
-    let mut draw_mass = |ui: &mut Ui, entity: Entity, mass: &mut Mut<Mass>| {
-        let inner_mass = mass.bypass_change_detection();
-        let original_mass = inner_mass.mass;
+    let draw_mass = |ui: &mut Ui, mass: &mut Mut<Mass>| {
+        let Mass::Mass(inner_mass) = mass.bypass_change_detection() else {
+            return;
+        };
 
         ui.label("Mass");
-        let mass_slider = egui::Slider::new(&mut inner_mass.mass, 0.0..=1e20)
+        let mass_slider = egui::Slider::new(inner_mass, 0.0..=1e20)
             .logarithmic(true)
             .custom_formatter(|x, _| format!("{}g", format_num!(".0s", x)));
         if ui.add(mass_slider).changed() {
-            mass_changed.send(MassChanged {
-                entity: entity,
-                old_mass: original_mass,
-                new_mass: mass.mass,
-            });
             mass.set_changed();
         }
     };
 
-    let mut draw_orbit = |ui: &mut Ui, entity: Entity, orbit: &mut Mut<Orbit>| {
+    let draw_orbit = |ui: &mut Ui, orbit: &mut Mut<Orbit>| {
         let inner_orbit = orbit.bypass_change_detection();
-        let original_orbit = inner_orbit.clone();
         let mut changed = false;
 
         ui.label("Semi-major axis");
-        changed |= ui.add(egui::Slider::new(&mut orbit.semi_major_axis, 0.0..=5.0)).changed();
+        changed |= ui.add(egui::Slider::new(&mut inner_orbit.semi_major_axis, 0.0..=5.0)).changed();
 
         ui.label("Eccentricity");
-        changed |= ui.add(egui::Slider::new(&mut orbit.eccentricity, 0.0..=1.0)).changed();
+        changed |= ui.add(egui::Slider::new(&mut inner_orbit.eccentricity, 0.0..=1.0)).changed();
 
         ui.label("Argument of periapsis");
-        changed |= ui.add(egui::Slider::new(&mut orbit.argument_of_periapsis, 0.0..=TAU)).changed();
+        changed |= ui.add(egui::Slider::new(&mut inner_orbit.argument_of_periapsis, 0.0..=TAU)).changed();
+
+        ui.label("Inclination");
+        changed |= ui.add(egui::Slider::new(&mut inner_orbit.inclination, 0.0..=PI)).changed();
+
+        ui.label("Longitude of ascending node");
+        changed |= ui
+            .add(egui::Slider::new(
+                &mut inner_orbit.longitude_of_ascending_node,
+                0.0..=TAU,
+            ))
+            .changed();
 
         ui.label("Initial mean anomaly");
-        changed |= ui.add(egui::Slider::new(&mut orbit.initial_mean_anomaly, 0.0..=TAU)).changed();
+        changed |= ui.add(egui::Slider::new(&mut inner_orbit.initial_mean_anomaly, 0.0..=TAU)).changed();
 
         if changed {
-            orbit_changed.send(OrbitChanged {
-                entity: entity,
-                old_orbit: original_orbit,
-                new_orbit: orbit.clone(),
-            });
             orbit.set_changed();
         }
     };
 
     egui::Window::new("Parameters").resizable(false).show(egui_contexts.ctx_mut(), |ui| {
+        ui.heading("Time");
+        ui.checkbox(&mut orbital_time.paused, "Paused");
+        ui.label("Time scale");
+        ui.add(egui::Slider::new(&mut orbital_time.time_scale, 0.1..=100.0).logarithmic(true));
+        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+
         ui.heading("Sun");
         let mut suns = queries.p0();
-        let (entity, mut mass) = suns.single_mut();
-        draw_mass(ui, entity, &mut mass);
+        let mut mass = suns.single_mut();
+        draw_mass(ui, &mut mass);
         ui.add_space(ui.spacing().item_spacing.y * 2.0);
 
         ui.heading("Earth");
         let mut earths = queries.p1();
-        let (entity, mut orbit, mut mass) = earths.single_mut();
-        draw_mass(ui, entity, &mut mass);
-        draw_orbit(ui, entity, &mut orbit);
+        let (mut orbit, mut mass) = earths.single_mut();
+        draw_mass(ui, &mut mass);
+        draw_orbit(ui, &mut orbit);
         ui.add_space(ui.spacing().item_spacing.y * 2.0);
 
         ui.heading("Moon");
         let mut moons = queries.p2();
-        let (entity, mut orbit) = moons.single_mut();
-        draw_orbit(ui, entity, &mut orbit);
+        let mut orbit = moons.single_mut();
+        draw_orbit(ui, &mut orbit);
     });
 }
 
@@ -180,6 +190,6 @@ pub fn draw_orbits(mut gizmos: Gizmos, orbits: Query<(&Orbit, &GlobalTransform,
             .map(|(_, parent_transform, _)| parent_transform.translation())
             .unwrap_or(Vec3::ZERO);
 
-        draw_orbit(&mut gizmos, &orbit, parent_position);
+        draw_orbit(&mut gizmos, orbit, parent_position);
     }
 }
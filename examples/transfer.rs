@@ -7,7 +7,7 @@ use utils::draw_orbit;
 #[bevy_main]
 fn main() {
     App::new()
-        .add_plugins((DefaultPlugins, OrbitPlugin))
+        .add_plugins((DefaultPlugins, OrbitPlugin::default()))
         .add_systems(Startup, startup)
         .add_systems(Update, draw_orbits)
         .run();
@@ -20,7 +20,12 @@ pub struct Orbits {
     target: Orbit,
 }
 
-fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
+fn startup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    gravitational_constant: Res<GravitationalConstant>,
+) {
     commands.spawn(Camera3dBundle {
         transform: Transform::from_xyz(0.0, 10.0, 0.0).looking_at(Vec3::ZERO, Vec3::NEG_Z),
         ..default()
@@ -34,6 +39,7 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
         ..default()
     });
 
+    let sun_mass = Mass::Mass(1e11);
     let sun = commands
         .spawn((
             PbrBundle {
@@ -41,7 +47,7 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
                 material: material.clone(),
                 ..default()
             },
-            Mass { mass: 1e11 },
+            sun_mass,
         ))
         .id();
 
@@ -49,6 +55,8 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
         semi_major_axis: 2.0,
         eccentricity: 0.0,
         argument_of_periapsis: 0.0,
+        inclination: 0.0,
+        longitude_of_ascending_node: 0.0,
         initial_mean_anomaly: 0.0,
     };
 
@@ -56,10 +64,18 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
         semi_major_axis: 4.0,
         eccentricity: 0.0,
         argument_of_periapsis: 0.0,
+        inclination: 0.0,
+        longitude_of_ascending_node: 0.0,
         initial_mean_anomaly: 0.0,
     };
 
-    let transfer = calculate_hohmann_transfer(&initial_orbit, &target_orbit, 1e11, 2.0);
+    let transfer = calculate_hohmann_transfer(
+        &initial_orbit,
+        &target_orbit,
+        sun_mass.gravitational_parameter(&gravitational_constant),
+        2.0,
+    )
+    .unwrap();
     let transfer_orbit = transfer.maneuvers.front().unwrap().target_orbit.clone();
     let mut schedule = TransferSchedule::default();
     schedule.push_transfer(transfer);
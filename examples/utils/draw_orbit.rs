@@ -1,21 +1,31 @@
-use std::f32::consts::PI;
-
 pub use bevy::prelude::*;
+use bevy_orbits::math::consts::PI;
+use bevy_orbits::math::{as_vec3, calculate_semilatus_rectum, rotate_from_orbital_plane, Conic, Scalar, Vector};
 pub use bevy_orbits::prelude::Orbit;
 
+// Draws the orbit as a line strip, stopping short of the asymptotes of open trajectories.
 #[allow(unused)]
 pub fn draw_orbit(gizmos: &mut Gizmos, orbit: &Orbit, parent_position: Vec3) {
-    let initial_rotation = Quat::from_rotation_x(PI / 2.0);
-    let argument_of_periapsis_rotation = Quat::from_rotation_y(-orbit.argument_of_periapsis);
-    let final_rotation = argument_of_periapsis_rotation * initial_rotation;
-
-    let half_size = Vec2::new(
-        orbit.semi_major_axis,
-        orbit.semi_major_axis * (1.0 - orbit.eccentricity.powi(2)).sqrt(),
-    );
+    let e = orbit.eccentricity;
+    let semilatus_rectum = calculate_semilatus_rectum(orbit.semi_major_axis, e);
+    let (max_true_anomaly, resolution) = match orbit.conic() {
+        Conic::Ellipse => (PI, 64),
+        Conic::Parabola => (0.9 * PI, 63),
+        Conic::Hyperbola => (0.9 * (-1.0 / e).acos(), 63),
+    };
 
-    let ellipse_center_offset = Vec3::new(-(half_size.x.powi(2) - half_size.y.powi(2)).sqrt(), 0.0, 0.0);
-    let ellipse_center = parent_position + argument_of_periapsis_rotation * ellipse_center_offset;
+    let points = (0..=resolution).map(|i| {
+        let true_anomaly = max_true_anomaly * (2.0 * i as Scalar / resolution as Scalar - 1.0);
+        let distance = semilatus_rectum / (1.0 + e * true_anomaly.cos());
+        let position = rotate_from_orbital_plane(
+            distance * true_anomaly.cos(),
+            -distance * true_anomaly.sin(),
+            orbit.argument_of_periapsis,
+            orbit.inclination,
+            orbit.longitude_of_ascending_node,
+        );
+        parent_position + as_vec3(Vector::from(position))
+    });
 
-    gizmos.ellipse(ellipse_center, final_rotation, half_size, Color::WHITE).resolution(64);
+    gizmos.linestrip(points, Color::WHITE);
 }
@@ -1,6 +1,3 @@
-mod dejitter_orbit;
 mod draw_orbit;
 
-#[allow(unused)]
-pub use dejitter_orbit::{dejitter_orbit, MassChanged, OrbitChanged};
 pub use draw_orbit::draw_orbit;
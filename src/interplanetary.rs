@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+
+use crate::math::*;
+use crate::orbit::Orbit;
+use crate::transfer::{calculate_lambert_transfer, cheapest_transfer, Maneuver, ParentChange, Transfer, TransferError};
+use crate::window::{calculate_transfer_window, TransferWindow};
+
+// Number of flight times tried for the legs inside the departure and arrival spheres of influence.
+const SEARCH_STEPS: usize = 128;
+
+// A body that an interplanetary transfer departs from or arrives at, with its orbit around the
+// parent it shares with the other body.
+#[derive(Debug, Clone)]
+pub struct TransferBody {
+    pub gravitational_parameter: Scalar,
+    pub orbit: Orbit,
+}
+
+// Patched conic transfer from an orbit around the departure body to an orbit around the arrival
+// body, `arrival_entity`, where both bodies orbit `parent`. Between the bodies it follows the
+// transfer window found by `calculate_transfer_window`. The escape burn leaves the start orbit for
+// the edge of the departure body's sphere of influence, where a correction burn joins the transfer
+// between the bodies and the maneuver after it moves the body to `parent`. Arriving at the edge of
+// the arrival body's sphere of influence the body is moved to it, and a correction burn sets up
+// the capture burn onto the target orbit.
+//
+// Entities following the transfer have their parent changed by it, so don't need `PatchedConic`.
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_interplanetary_transfer(
+    start_orbit: &Orbit,
+    departure_body: &TransferBody,
+    target_orbit: &Orbit,
+    arrival_body: &TransferBody,
+    arrival_entity: Entity,
+    parent: Entity,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if start_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let departure_sphere_of_influence = calculate_sphere_of_influence(
+        departure_body.orbit.semi_major_axis,
+        departure_body.gravitational_parameter,
+        gravitational_parameter,
+    );
+    let arrival_sphere_of_influence = calculate_sphere_of_influence(
+        arrival_body.orbit.semi_major_axis,
+        arrival_body.gravitational_parameter,
+        gravitational_parameter,
+    );
+    let start_period = calculate_period(start_orbit.semi_major_axis, departure_body.gravitational_parameter);
+    let target_period = calculate_period(target_orbit.semi_major_axis, arrival_body.gravitational_parameter);
+
+    // the escape has to fit between the execution time and the window's departure
+    let escape_flight_times = |window: &TransferWindow| -> Option<(Scalar, Scalar)> {
+        let (departure_excess_velocity, _) =
+            calculate_excess_velocities(departure_body, arrival_body, gravitational_parameter, window)?;
+        let escape_time = departure_sphere_of_influence / departure_excess_velocity.length();
+        Some((0.25 * escape_time, 1.5 * escape_time + start_period))
+    };
+    let mut window = calculate_transfer_window(
+        &departure_body.orbit,
+        &arrival_body.orbit,
+        gravitational_parameter,
+        execution_time,
+    )?;
+    let (_, longest_escape) = escape_flight_times(&window).ok_or(TransferError::NoSolution)?;
+    if window.departure_time - longest_escape < execution_time {
+        window = calculate_transfer_window(
+            &departure_body.orbit,
+            &arrival_body.orbit,
+            gravitational_parameter,
+            execution_time + longest_escape,
+        )?;
+    }
+    let (shortest_escape, longest_escape) = escape_flight_times(&window).ok_or(TransferError::NoSolution)?;
+    let (departure_excess_velocity, arrival_excess_velocity) =
+        calculate_excess_velocities(departure_body, arrival_body, gravitational_parameter, &window)
+            .ok_or(TransferError::NoSolution)?;
+
+    // the transfer between the bodies runs between the edges of their spheres of influence, in
+    // the directions the body leaves and arrives
+    let departure_time = window.departure_time;
+    let arrival_time = window.arrival_time();
+    let (departure_body_position, departure_body_velocity) =
+        departure_body.orbit.state_at(departure_time, gravitational_parameter);
+    let (arrival_body_position, arrival_body_velocity) =
+        arrival_body.orbit.state_at(arrival_time, gravitational_parameter);
+    let exit_position = departure_excess_velocity.normalize() * departure_sphere_of_influence;
+    let entry_position = -arrival_excess_velocity.normalize() * arrival_sphere_of_influence;
+
+    let (_, _, normal) = departure_body.orbit.perifocal_axes();
+    let (exit_velocity, entry_velocity) = calculate_lambert_velocities(
+        departure_body_position + exit_position,
+        arrival_body_position + entry_position,
+        arrival_time - departure_time,
+        normal,
+        gravitational_parameter,
+    )
+    .ok_or(TransferError::NoSolution)?;
+
+    let exit_orbit = Orbit::from_state_vectors(
+        exit_position,
+        exit_velocity - departure_body_velocity,
+        departure_body.gravitational_parameter,
+        departure_time,
+    );
+    let transfer_orbit = Orbit::from_state_vectors(
+        departure_body_position + exit_position,
+        exit_velocity,
+        gravitational_parameter,
+        departure_time,
+    );
+    let approach_orbit = Orbit::from_state_vectors(
+        entry_position,
+        entry_velocity - arrival_body_velocity,
+        arrival_body.gravitational_parameter,
+        arrival_time,
+    );
+
+    let escape = calculate_cheapest_lambert_leg(
+        sample_range(shortest_escape..=longest_escape, SEARCH_STEPS)
+            .into_iter()
+            .map(|flight_time| departure_time - flight_time)
+            .filter(|&escape_time| escape_time >= execution_time)
+            .map(|escape_time| (escape_time, departure_time)),
+        start_orbit,
+        &exit_orbit,
+        departure_body.gravitational_parameter,
+    )?;
+
+    let capture_time = arrival_sphere_of_influence / arrival_excess_velocity.length();
+    let capture = calculate_cheapest_lambert_leg(
+        sample_range(0.25 * capture_time..=1.5 * capture_time + target_period, SEARCH_STEPS)
+            .into_iter()
+            .map(|flight_time| (arrival_time, arrival_time + flight_time)),
+        &approach_orbit,
+        target_orbit,
+        arrival_body.gravitational_parameter,
+    )?;
+
+    let exit = Maneuver {
+        start_orbit: exit_orbit,
+        target_orbit: transfer_orbit.clone(),
+        execution_time: departure_time,
+        parent_change: Some(ParentChange {
+            parent,
+            gravitational_parameter,
+        }),
+    };
+    let entry = Maneuver {
+        start_orbit: transfer_orbit,
+        target_orbit: approach_orbit,
+        execution_time: arrival_time,
+        parent_change: Some(ParentChange {
+            parent: arrival_entity,
+            gravitational_parameter: arrival_body.gravitational_parameter,
+        }),
+    };
+
+    let mut maneuvers = escape.maneuvers;
+    maneuvers.push_back(exit);
+    maneuvers.push_back(entry);
+    maneuvers.extend(capture.maneuvers);
+    Ok(Transfer { maneuvers })
+}
+
+// Velocities relative to the departure and arrival bodies at the start and end of the window.
+fn calculate_excess_velocities(
+    departure_body: &TransferBody,
+    arrival_body: &TransferBody,
+    gravitational_parameter: Scalar,
+    window: &TransferWindow,
+) -> Option<(Vector, Vector)> {
+    let (departure_position, departure_velocity) =
+        departure_body.orbit.state_at(window.departure_time, gravitational_parameter);
+    let (arrival_position, arrival_velocity) =
+        arrival_body.orbit.state_at(window.arrival_time(), gravitational_parameter);
+    let (_, _, normal) = departure_body.orbit.perifocal_axes();
+    let (start_velocity, end_velocity) = calculate_lambert_velocities(
+        departure_position,
+        arrival_position,
+        window.time_of_flight,
+        normal,
+        gravitational_parameter,
+    )?;
+    Some((start_velocity - departure_velocity, end_velocity - arrival_velocity))
+}
+
+// The cheapest of the Lambert transfers departing and arriving at the given pairs of times.
+fn calculate_cheapest_lambert_leg(
+    times: impl Iterator<Item = (Scalar, Scalar)>,
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+) -> Result<Transfer, TransferError> {
+    let transfers = times.filter_map(|(departure_time, arrival_time)| {
+        calculate_lambert_transfer(
+            start_orbit,
+            target_orbit,
+            gravitational_parameter,
+            departure_time,
+            arrival_time,
+        )
+        .ok()
+    });
+    cheapest_transfer(transfers, gravitational_parameter)
+}
@@ -1,10 +1,28 @@
+mod interplanetary;
 pub mod math;
 mod orbit;
+mod plane_change;
 mod plugin;
+mod rendezvous;
+mod soi;
+mod time;
 mod transfer;
+mod velocity;
+mod window;
 
 pub mod prelude {
-    pub use crate::orbit::{Mass, Orbit};
+    pub use crate::interplanetary::{calculate_interplanetary_transfer, TransferBody};
+    pub use crate::orbit::{GravitationalConstant, Mass, Orbit, OrbitScale, PreserveTrueAnomaly};
+    pub use crate::plane_change::{calculate_combined_plane_change_transfer, calculate_plane_change_transfer};
     pub use crate::plugin::OrbitPlugin;
-    pub use crate::transfer::{calculate_hohmann_transfer, Maneuver, Transfer, TransferSchedule};
+    pub use crate::rendezvous::{calculate_phasing_transfer, calculate_rendezvous_transfer};
+    pub use crate::soi::{PatchedConic, SphereOfInfluenceChanged};
+    pub use crate::time::OrbitalTime;
+    pub use crate::transfer::{
+        calculate_bi_elliptic_transfer, calculate_cheapest_transfer, calculate_hohmann_transfer,
+        calculate_lambert_transfer, ExecutedManeuver, Maneuver, ManeuverExecuted, ParentChange, Transfer,
+        TransferCompleted, TransferError, TransferSchedule, TransferStarted,
+    };
+    pub use crate::velocity::OrbitalVelocity;
+    pub use crate::window::{calculate_porkchop, calculate_transfer_window, Porkchop, TransferWindow};
 }
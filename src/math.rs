@@ -1,85 +1,918 @@
-use std::f32::consts::{PI, TAU};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 
-const G: f32 = 6.67384e-11;
+use bevy::math::{Quat, Vec3};
 
+use self::consts::{PI, TAU};
+
+// Orbits are calculated in single precision by default, or double precision with the `f64`
+// feature, which real-scale solar systems need to avoid jitter.
+#[cfg(not(feature = "f64"))]
+pub type Scalar = f32;
+#[cfg(feature = "f64")]
+pub type Scalar = f64;
+
+#[cfg(not(feature = "f64"))]
+pub type Vector = bevy::math::Vec3;
+#[cfg(feature = "f64")]
+pub type Vector = bevy::math::DVec3;
+
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
+
+// The gravitational constant in SI units, used unless the app sets its own.
+pub const GRAVITATIONAL_CONSTANT: Scalar = 6.67384e-11;
+
+// Below this an eccentricity is considered circular, and orbital planes equatorial or coplanar.
+pub const ORBIT_TOLERANCE: Scalar = 1e-6;
+
+// Eccentricities this close to 1 are treated as parabolic, with the periapsis distance from
+// `calculate_periapsis_distance`.
+const PARABOLIC_TOLERANCE: Scalar = 1e-6;
+
+// Relative step size at which iterative solvers stop.
+const SOLVER_TOLERANCE: Scalar = 1e2 * Scalar::EPSILON;
+
+// Most iterations the Kepler equation solver takes before giving up.
+const KEPLER_MAX_ITERATIONS: u32 = 50;
+
+#[cfg(not(feature = "f64"))]
+#[inline]
+pub fn as_seconds(duration: Duration) -> Scalar {
+    duration.as_secs_f32()
+}
+
+#[cfg(feature = "f64")]
+#[inline]
+pub fn as_seconds(duration: Duration) -> Scalar {
+    duration.as_secs_f64()
+}
+
+// Widens a scalar to double precision, for sums that have to stay exact over a long time.
+#[cfg(not(feature = "f64"))]
+#[inline]
+pub fn as_f64(value: Scalar) -> f64 {
+    value as f64
+}
+
+#[cfg(feature = "f64")]
+#[inline]
+pub fn as_f64(value: Scalar) -> f64 {
+    value
+}
+
+// Narrows a vector to single precision, for writing into a `Transform`.
+#[cfg(not(feature = "f64"))]
+#[inline]
+pub fn as_vec3(vector: Vector) -> Vec3 {
+    vector
+}
+
+#[cfg(feature = "f64")]
+#[inline]
+pub fn as_vec3(vector: Vector) -> Vec3 {
+    vector.as_vec3()
+}
+
+#[cfg(not(feature = "f64"))]
 #[inline]
+pub fn from_vec3(vec3: Vec3) -> Vector {
+    vec3
+}
+
+#[cfg(feature = "f64")]
+#[inline]
+pub fn from_vec3(vec3: Vec3) -> Vector {
+    vec3.as_dvec3()
+}
+
+// Rotates a vector by a `Transform` rotation, without narrowing it to single precision.
+#[cfg(not(feature = "f64"))]
+#[inline]
+pub fn rotate(rotation: Quat, vector: Vector) -> Vector {
+    rotation * vector
+}
+
+#[cfg(feature = "f64")]
+#[inline]
+pub fn rotate(rotation: Quat, vector: Vector) -> Vector {
+    rotation.as_dquat() * vector
+}
+
+// Outcome of an iterative solver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Convergence {
+    pub value: Scalar,
+    pub iterations: u32,
+    // How far the solution is from satisfying the equation being solved.
+    pub residual: Scalar,
+    pub converged: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conic {
+    Ellipse,
+    Parabola,
+    Hyperbola,
+}
+
+impl Conic {
+    pub fn from_eccentricity(eccentricity: Scalar) -> Conic {
+        if (eccentricity - 1.0).abs() < PARABOLIC_TOLERANCE {
+            Conic::Parabola
+        } else if eccentricity < 1.0 {
+            Conic::Ellipse
+        } else {
+            Conic::Hyperbola
+        }
+    }
+}
+
+#[inline]
+#[allow(clippy::too_many_arguments)]
 pub fn calculate_position_at_time(
-    semi_major_axis: f32,
-    eccentricity: f32,
-    argument_of_periapsis: f32,
-    initial_mean_anomaly: f32,
-    parent_mass: f32,
-    time: f32,
-) -> (f32, f32, f32) {
-    let period = calculate_period(semi_major_axis, parent_mass);
-    let mean_motion = calculate_mean_motion(period);
-    let mean_anomaly = calculate_mean_anomaly(mean_motion, initial_mean_anomaly, time);
-    let eccentric_anomaly = calculate_eccentric_anomaly(eccentricity, mean_anomaly);
-    let true_anomaly = calculate_true_anomaly(eccentricity, eccentric_anomaly);
-    let heliocentric_distance = calculate_heliocentric_distance(semi_major_axis, eccentricity, true_anomaly);
-    calculate_position(true_anomaly, heliocentric_distance, argument_of_periapsis, mean_anomaly)
+    semi_major_axis: Scalar,
+    eccentricity: Scalar,
+    argument_of_periapsis: Scalar,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+    initial_mean_anomaly: Scalar,
+    gravitational_parameter: Scalar,
+    time: Scalar,
+) -> (Scalar, Scalar, Scalar) {
+    let true_anomaly = calculate_true_anomaly_at_time(
+        semi_major_axis,
+        eccentricity,
+        initial_mean_anomaly,
+        gravitational_parameter,
+        time,
+    );
+    let semilatus_rectum = calculate_semilatus_rectum(semi_major_axis, eccentricity);
+    let heliocentric_distance = semilatus_rectum / (1.0 + eccentricity * true_anomaly.cos());
+    calculate_position(
+        true_anomaly,
+        heliocentric_distance,
+        argument_of_periapsis,
+        inclination,
+        longitude_of_ascending_node,
+    )
+}
+
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_velocity_at_time(
+    semi_major_axis: Scalar,
+    eccentricity: Scalar,
+    argument_of_periapsis: Scalar,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+    initial_mean_anomaly: Scalar,
+    gravitational_parameter: Scalar,
+    time: Scalar,
+) -> (Scalar, Scalar, Scalar) {
+    let true_anomaly = calculate_true_anomaly_at_time(
+        semi_major_axis,
+        eccentricity,
+        initial_mean_anomaly,
+        gravitational_parameter,
+        time,
+    );
+    let semilatus_rectum = calculate_semilatus_rectum(semi_major_axis, eccentricity);
+    calculate_velocity(
+        true_anomaly,
+        semilatus_rectum,
+        eccentricity,
+        argument_of_periapsis,
+        inclination,
+        longitude_of_ascending_node,
+        gravitational_parameter,
+    )
+}
+
+// Signed true anomaly, negative while a body is travelling from apoapsis (or infinity) to periapsis.
+#[inline]
+pub fn calculate_true_anomaly_at_time(
+    semi_major_axis: Scalar,
+    eccentricity: Scalar,
+    initial_mean_anomaly: Scalar,
+    gravitational_parameter: Scalar,
+    time: Scalar,
+) -> Scalar {
+    match Conic::from_eccentricity(eccentricity) {
+        Conic::Ellipse => {
+            let period = calculate_period(semi_major_axis, gravitational_parameter);
+            let mean_motion = calculate_mean_motion(period);
+            let mean_anomaly = calculate_mean_anomaly(mean_motion, initial_mean_anomaly, time);
+            let eccentric_anomaly = calculate_eccentric_anomaly(eccentricity, mean_anomaly);
+            let true_anomaly = calculate_true_anomaly(eccentricity, eccentric_anomaly);
+            if true_anomaly <= PI {
+                true_anomaly
+            } else {
+                true_anomaly - TAU
+            }
+        }
+        Conic::Parabola => {
+            let periapsis_distance = calculate_periapsis_distance(semi_major_axis, eccentricity);
+            let mean_motion = calculate_parabolic_mean_motion(periapsis_distance, gravitational_parameter);
+            let mean_anomaly = calculate_unbounded_mean_anomaly(mean_motion, initial_mean_anomaly, time);
+            let parabolic_anomaly = calculate_parabolic_anomaly(mean_anomaly);
+            calculate_parabolic_true_anomaly(parabolic_anomaly)
+        }
+        Conic::Hyperbola => {
+            let mean_motion = calculate_hyperbolic_mean_motion(semi_major_axis, gravitational_parameter);
+            let mean_anomaly = calculate_unbounded_mean_anomaly(mean_motion, initial_mean_anomaly, time);
+            let hyperbolic_anomaly = calculate_hyperbolic_anomaly(eccentricity, mean_anomaly);
+            calculate_hyperbolic_true_anomaly(eccentricity, hyperbolic_anomaly)
+        }
+    }
+}
+
+#[inline]
+pub fn calculate_gravitational_parameter(gravitational_constant: Scalar, mass: Scalar) -> Scalar {
+    gravitational_constant * mass
+}
+
+// For an eccentricity of exactly 1 the semi-major axis is infinite, so `Orbit::semi_major_axis`
+// holds the periapsis distance instead. Either sign is accepted.
+#[inline]
+pub fn calculate_periapsis_distance(semi_major_axis: Scalar, eccentricity: Scalar) -> Scalar {
+    if eccentricity == 1.0 {
+        semi_major_axis.abs()
+    } else {
+        (semi_major_axis * (1.0 - eccentricity)).abs()
+    }
 }
 
 #[inline]
-pub fn calculate_period(semi_major_axis: f32, parent_mass: f32) -> f32 {
-    TAU * (semi_major_axis.powi(3) / (G * parent_mass)).sqrt()
+pub fn calculate_semilatus_rectum(semi_major_axis: Scalar, eccentricity: Scalar) -> Scalar {
+    match Conic::from_eccentricity(eccentricity) {
+        Conic::Ellipse => semi_major_axis * (1.0 - eccentricity.powi(2)),
+        Conic::Parabola => 2.0 * calculate_periapsis_distance(semi_major_axis, eccentricity),
+        Conic::Hyperbola => semi_major_axis.abs() * (eccentricity.powi(2) - 1.0),
+    }
 }
 
 #[inline]
-pub fn calculate_mean_motion(period: f32) -> f32 {
+pub fn calculate_vis_viva_speed(
+    heliocentric_distance: Scalar,
+    semi_major_axis: Scalar,
+    gravitational_parameter: Scalar,
+) -> Scalar {
+    (gravitational_parameter * (2.0 / heliocentric_distance - 1.0 / semi_major_axis)).sqrt()
+}
+
+// Mean motion of any conic, in radians of mean anomaly per second.
+#[inline]
+pub fn calculate_conic_mean_motion(
+    semi_major_axis: Scalar,
+    eccentricity: Scalar,
+    gravitational_parameter: Scalar,
+) -> Scalar {
+    match Conic::from_eccentricity(eccentricity) {
+        Conic::Ellipse => calculate_mean_motion(calculate_period(semi_major_axis, gravitational_parameter)),
+        Conic::Parabola => calculate_parabolic_mean_motion(
+            calculate_periapsis_distance(semi_major_axis, eccentricity),
+            gravitational_parameter,
+        ),
+        Conic::Hyperbola => calculate_hyperbolic_mean_motion(semi_major_axis, gravitational_parameter),
+    }
+}
+
+// Takes a signed true anomaly for any conic, wrapping the result for elliptic orbits.
+#[inline]
+pub fn calculate_mean_anomaly_from_true_anomaly(eccentricity: Scalar, true_anomaly: Scalar) -> Scalar {
+    match Conic::from_eccentricity(eccentricity) {
+        Conic::Ellipse => {
+            let eccentric_anomaly = calculate_eccentric_anomaly_from_true_anomaly(eccentricity, true_anomaly);
+            calculate_mean_anomaly_from_eccentric_anomaly(eccentricity, eccentric_anomaly)
+        }
+        Conic::Parabola => {
+            let parabolic_anomaly = calculate_parabolic_anomaly_from_true_anomaly(true_anomaly);
+            calculate_mean_anomaly_from_parabolic_anomaly(parabolic_anomaly)
+        }
+        Conic::Hyperbola => {
+            let hyperbolic_anomaly = calculate_hyperbolic_anomaly_from_true_anomaly(eccentricity, true_anomaly);
+            calculate_mean_anomaly_from_hyperbolic_anomaly(eccentricity, hyperbolic_anomaly)
+        }
+    }
+}
+
+// Only meaningful for elliptic orbits, open trajectories never repeat.
+#[inline]
+pub fn calculate_period(semi_major_axis: Scalar, gravitational_parameter: Scalar) -> Scalar {
+    TAU * (semi_major_axis.powi(3) / gravitational_parameter).sqrt()
+}
+
+#[inline]
+pub fn calculate_semi_major_axis(period: Scalar, gravitational_parameter: Scalar) -> Scalar {
+    (gravitational_parameter * (period / TAU).powi(2)).cbrt()
+}
+
+#[inline]
+pub fn calculate_mean_motion(period: Scalar) -> Scalar {
     TAU / period
 }
 
+// Distance within which a body's gravity dominates its parent's, from the Laplace approximation.
 #[inline]
-pub fn calculate_mean_anomaly(mean_motion: f32, initial_mean_anomaly: f32, time: f32) -> f32 {
+pub fn calculate_sphere_of_influence(
+    semi_major_axis: Scalar,
+    gravitational_parameter: Scalar,
+    parent_gravitational_parameter: Scalar,
+) -> Scalar {
+    semi_major_axis.abs() * (gravitational_parameter / parent_gravitational_parameter).powf(0.4)
+}
+
+// Time between successive alignments of two bodies with the given periods, infinite if they're
+// the same.
+#[inline]
+pub fn calculate_synodic_period(period_1: Scalar, period_2: Scalar) -> Scalar {
+    (period_1 * period_2 / (period_1 - period_2)).abs()
+}
+
+// How far the target has to lead the body (between -PI and PI) when a Hohmann transfer between
+// circular orbits departs, for both to arrive at the opposite side of the parent together.
+#[inline]
+pub fn calculate_hohmann_phase_angle(
+    start_semi_major_axis: Scalar,
+    target_semi_major_axis: Scalar,
+    gravitational_parameter: Scalar,
+) -> Scalar {
+    let transfer_time = calculate_period(
+        (start_semi_major_axis + target_semi_major_axis) / 2.0,
+        gravitational_parameter,
+    ) / 2.0;
+    let target_mean_motion = calculate_mean_motion(calculate_period(target_semi_major_axis, gravitational_parameter));
+    (TAU - target_mean_motion * transfer_time).rem_euclid(TAU) - PI
+}
+
+// Time until the phase angle between two bodies, which grows at their relative mean motion, next
+// reaches the required angle. Infinite if the phase angle doesn't change.
+#[inline]
+pub fn calculate_time_to_phase_angle(
+    phase_angle: Scalar,
+    required_phase_angle: Scalar,
+    relative_mean_motion: Scalar,
+) -> Scalar {
+    if relative_mean_motion == 0.0 {
+        return Scalar::INFINITY;
+    }
+    ((required_phase_angle - phase_angle) * relative_mean_motion.signum()).rem_euclid(TAU) / relative_mean_motion.abs()
+}
+
+#[inline]
+pub fn calculate_mean_anomaly(mean_motion: Scalar, initial_mean_anomaly: Scalar, time: Scalar) -> Scalar {
     (initial_mean_anomaly + mean_motion * time).rem_euclid(TAU)
 }
 
 #[inline]
-pub fn calculate_initial_mean_anomaly(mean_anomaly: f32, period: f32, time: f32) -> f32 {
+pub fn calculate_initial_mean_anomaly(mean_anomaly: Scalar, period: Scalar, time: Scalar) -> Scalar {
     let mean_motion = calculate_mean_motion(period);
     (mean_anomaly - mean_motion * time).rem_euclid(TAU)
 }
 
 #[inline]
-pub fn calculate_eccentric_anomaly(eccentricity: f32, mean_anomaly: f32) -> f32 {
+pub fn calculate_eccentric_anomaly(eccentricity: Scalar, mean_anomaly: Scalar) -> Scalar {
+    calculate_eccentric_anomaly_with_convergence(eccentricity, mean_anomaly).value
+}
+
+// Solves Kepler's equation with the Laguerre-Conway method, which converges from the starting
+// guess for every elliptic eccentricity, reporting how it went. The result is in the same turn
+// as the mean anomaly.
+pub fn calculate_eccentric_anomaly_with_convergence(eccentricity: Scalar, mean_anomaly: Scalar) -> Convergence {
+    const DEGREE: Scalar = 5.0;
+
     let e = eccentricity;
-    let ma = mean_anomaly;
-    let mut ea = ma;
-    // using Newton's method
-    for _i in 0..5 {
-        ea = ea - (ea - e * ea.sin() - ma) / (1.0 - e * ea.cos());
+    let turn = mean_anomaly - mean_anomaly.rem_euclid(TAU);
+    let ma = mean_anomaly - turn;
+    // Danby's starting guess, which stays on the right side of periapsis for high eccentricities
+    let mut ea = ma + 0.85 * e * (PI - ma).signum();
+
+    let mut iterations = 0;
+    let mut converged = false;
+    while iterations < KEPLER_MAX_ITERATIONS {
+        iterations += 1;
+        let f = ea - e * ea.sin() - ma;
+        let f_prime = 1.0 - e * ea.cos();
+        let f_prime_prime = e * ea.sin();
+        let discriminant =
+            ((DEGREE - 1.0).powi(2) * f_prime.powi(2) - DEGREE * (DEGREE - 1.0) * f * f_prime_prime).abs();
+        let step = DEGREE * f / (f_prime + f_prime.signum() * discriminant.sqrt());
+        ea -= step;
+        if step.abs() <= SOLVER_TOLERANCE * ea.abs().max(1.0) {
+            converged = true;
+            break;
+        }
+    }
+
+    Convergence {
+        value: ea + turn,
+        iterations,
+        residual: (ea - e * ea.sin() - ma).abs(),
+        converged,
     }
-    ea
 }
 
+// Quadrant-correct, giving a true anomaly in the same turn as the eccentric anomaly.
 #[inline]
-pub fn calculate_true_anomaly(eccentricity: f32, eccentric_anomaly: f32) -> f32 {
-    let e = eccentricity;
-    let e_a = eccentric_anomaly;
-    2.0 * (((1.0 + e) / (1.0 - e) * ((e_a / 2.0).tan()).powi(2)).sqrt()).atan()
+pub fn calculate_true_anomaly(eccentricity: Scalar, eccentric_anomaly: Scalar) -> Scalar {
+    let beta = eccentricity / (1.0 + (1.0 - eccentricity.powi(2)).sqrt());
+    eccentric_anomaly + 2.0 * (beta * eccentric_anomaly.sin()).atan2(1.0 - beta * eccentric_anomaly.cos())
 }
 
 #[inline]
-pub fn calculate_heliocentric_distance(semi_major_axis: f32, eccentricity: f32, true_anomaly: f32) -> f32 {
+pub fn calculate_heliocentric_distance(semi_major_axis: Scalar, eccentricity: Scalar, true_anomaly: Scalar) -> Scalar {
     let semilatus_rectum = semi_major_axis * (1.0 - eccentricity.powi(2));
     semilatus_rectum / (1.0 + eccentricity * true_anomaly.cos())
 }
 
+// The inverse of `calculate_true_anomaly`, giving an eccentric anomaly in the same turn as the
+// true anomaly, so a signed true anomaly gives a signed eccentric anomaly.
+#[inline]
+pub fn calculate_eccentric_anomaly_from_true_anomaly(eccentricity: Scalar, true_anomaly: Scalar) -> Scalar {
+    let beta = eccentricity / (1.0 + (1.0 - eccentricity.powi(2)).sqrt());
+    true_anomaly - 2.0 * (beta * true_anomaly.sin()).atan2(1.0 + beta * true_anomaly.cos())
+}
+
+#[inline]
+pub fn calculate_mean_anomaly_from_eccentric_anomaly(eccentricity: Scalar, eccentric_anomaly: Scalar) -> Scalar {
+    (eccentric_anomaly - eccentricity * eccentric_anomaly.sin()).rem_euclid(TAU)
+}
+
+// Mean anomaly for open trajectories, which is not wrapped as it never repeats.
+#[inline]
+pub fn calculate_unbounded_mean_anomaly(mean_motion: Scalar, initial_mean_anomaly: Scalar, time: Scalar) -> Scalar {
+    initial_mean_anomaly + mean_motion * time
+}
+
+// A hyperbolic orbit's semi-major axis may be given with either sign, only its magnitude is used.
+#[inline]
+pub fn calculate_hyperbolic_mean_motion(semi_major_axis: Scalar, gravitational_parameter: Scalar) -> Scalar {
+    (gravitational_parameter / semi_major_axis.abs().powi(3)).sqrt()
+}
+
+#[inline]
+pub fn calculate_hyperbolic_anomaly(eccentricity: Scalar, mean_anomaly: Scalar) -> Scalar {
+    let e = eccentricity;
+    let ma = mean_anomaly;
+    let mut ha = ma.signum() * (2.0 * ma.abs() / e + 1.8).ln();
+    // using Newton's method on the hyperbolic Kepler equation, which needs more iterations
+    // than the elliptic case far from periapsis
+    for _i in 0..50 {
+        let step = (e * ha.sinh() - ha - ma) / (e * ha.cosh() - 1.0);
+        ha -= step;
+        if step.abs() <= SOLVER_TOLERANCE * ha.abs().max(1.0) {
+            break;
+        }
+    }
+    ha
+}
+
+#[inline]
+pub fn calculate_hyperbolic_true_anomaly(eccentricity: Scalar, hyperbolic_anomaly: Scalar) -> Scalar {
+    let e = eccentricity;
+    2.0 * (((e + 1.0) / (e - 1.0)).sqrt() * (hyperbolic_anomaly / 2.0).tanh()).atan()
+}
+
+#[inline]
+pub fn calculate_hyperbolic_anomaly_from_true_anomaly(eccentricity: Scalar, true_anomaly: Scalar) -> Scalar {
+    let e = eccentricity;
+    2.0 * (((e - 1.0) / (e + 1.0)).sqrt() * (true_anomaly / 2.0).tan()).atanh()
+}
+
+#[inline]
+pub fn calculate_mean_anomaly_from_hyperbolic_anomaly(eccentricity: Scalar, hyperbolic_anomaly: Scalar) -> Scalar {
+    eccentricity * hyperbolic_anomaly.sinh() - hyperbolic_anomaly
+}
+
+#[inline]
+pub fn calculate_parabolic_mean_motion(periapsis_distance: Scalar, gravitational_parameter: Scalar) -> Scalar {
+    (gravitational_parameter / (2.0 * periapsis_distance.powi(3))).sqrt()
+}
+
+// Solves Barker's equation, `mean_anomaly = D + D^3 / 3` where `D = tan(true_anomaly / 2)`.
+#[inline]
+pub fn calculate_parabolic_anomaly(mean_anomaly: Scalar) -> Scalar {
+    2.0 * ((1.5 * mean_anomaly).asinh() / 3.0).sinh()
+}
+
+#[inline]
+pub fn calculate_parabolic_true_anomaly(parabolic_anomaly: Scalar) -> Scalar {
+    2.0 * parabolic_anomaly.atan()
+}
+
+#[inline]
+pub fn calculate_parabolic_anomaly_from_true_anomaly(true_anomaly: Scalar) -> Scalar {
+    (true_anomaly / 2.0).tan()
+}
+
+#[inline]
+pub fn calculate_mean_anomaly_from_parabolic_anomaly(parabolic_anomaly: Scalar) -> Scalar {
+    parabolic_anomaly + parabolic_anomaly.powi(3) / 3.0
+}
+
+// Takes a true anomaly for any conic, in any turn.
 #[inline]
 pub fn calculate_position(
-    true_anomaly: f32,
-    heliocentric_distance: f32,
-    argument_of_periapsis: f32,
-    mean_anomaly: f32,
-) -> (f32, f32, f32) {
-    let zmod = if (mean_anomaly % TAU) < PI { -1.0 } else { 1.0 };
+    true_anomaly: Scalar,
+    heliocentric_distance: Scalar,
+    argument_of_periapsis: Scalar,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+) -> (Scalar, Scalar, Scalar) {
+    rotate_from_orbital_plane(
+        heliocentric_distance * true_anomaly.cos(),
+        -heliocentric_distance * true_anomaly.sin(),
+        argument_of_periapsis,
+        inclination,
+        longitude_of_ascending_node,
+    )
+}
+
+// Takes a signed true anomaly, and works for any conic.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_velocity(
+    true_anomaly: Scalar,
+    semilatus_rectum: Scalar,
+    eccentricity: Scalar,
+    argument_of_periapsis: Scalar,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+    gravitational_parameter: Scalar,
+) -> (Scalar, Scalar, Scalar) {
+    let speed_factor = (gravitational_parameter / semilatus_rectum).sqrt();
+
+    let x = -speed_factor * true_anomaly.sin();
+    let z = -speed_factor * (eccentricity + true_anomaly.cos());
 
-    let x = heliocentric_distance * true_anomaly.cos();
-    let z = heliocentric_distance * true_anomaly.sin() * zmod;
+    rotate_from_orbital_plane(x, z, argument_of_periapsis, inclination, longitude_of_ascending_node)
+}
 
-    let rotated_x = x * argument_of_periapsis.cos() - z * argument_of_periapsis.sin();
-    let rotated_z = x * argument_of_periapsis.sin() + z * argument_of_periapsis.cos();
+// Rotates a point in the orbital plane (periapsis along +x) into the reference frame, where
+// the reference plane is XZ and the ascending node of an orbit with no longitude lies along +x.
+#[inline]
+pub fn rotate_from_orbital_plane(
+    x: Scalar,
+    z: Scalar,
+    argument_of_periapsis: Scalar,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+) -> (Scalar, Scalar, Scalar) {
+    // argument of periapsis, about the orbit normal
+    let x1 = x * argument_of_periapsis.cos() - z * argument_of_periapsis.sin();
+    let z1 = x * argument_of_periapsis.sin() + z * argument_of_periapsis.cos();
 
-    (rotated_x, 0.0, rotated_z)
+    // inclination, about the line of nodes
+    let y2 = -z1 * inclination.sin();
+    let z2 = z1 * inclination.cos();
+
+    // longitude of the ascending node, about the reference normal
+    let x3 = x1 * longitude_of_ascending_node.cos() - z2 * longitude_of_ascending_node.sin();
+    let z3 = x1 * longitude_of_ascending_node.sin() + z2 * longitude_of_ascending_node.cos();
+
+    (x3, y2, z3)
+}
+
+// Rotates a vector by the given angle about a unit axis, anticlockwise looking down the axis.
+#[inline]
+pub fn rotate_about_axis(vector: Vector, axis: Vector, angle: Scalar) -> Vector {
+    vector * angle.cos() + axis.cross(vector) * angle.sin() + axis * axis.dot(vector) * (1.0 - angle.cos())
+}
+
+#[inline]
+pub fn calculate_stumpff_c2(psi: Scalar) -> Scalar {
+    if psi > SOLVER_TOLERANCE {
+        (1.0 - psi.sqrt().cos()) / psi
+    } else if psi < -SOLVER_TOLERANCE {
+        ((-psi).sqrt().cosh() - 1.0) / -psi
+    } else {
+        1.0 / 2.0
+    }
+}
+
+#[inline]
+pub fn calculate_stumpff_c3(psi: Scalar) -> Scalar {
+    if psi > SOLVER_TOLERANCE {
+        let sqrt_psi = psi.sqrt();
+        (sqrt_psi - sqrt_psi.sin()) / (psi * sqrt_psi)
+    } else if psi < -SOLVER_TOLERANCE {
+        let sqrt_psi = (-psi).sqrt();
+        (sqrt_psi.sinh() - sqrt_psi) / (-psi * sqrt_psi)
+    } else {
+        1.0 / 6.0
+    }
+}
+
+// Solves Lambert's problem, finding the velocities at both ends of the trajectory that goes from
+// `start_position` to `end_position` in the given time without completing a revolution. The
+// trajectory travels prograde about `normal`. Returns `None` if no such trajectory exists, such
+// as when the positions are exactly opposite each other and the plane is undefined.
+pub fn calculate_lambert_velocities(
+    start_position: Vector,
+    end_position: Vector,
+    time_of_flight: Scalar,
+    normal: Vector,
+    gravitational_parameter: Scalar,
+) -> Option<(Vector, Vector)> {
+    let sqrt_mu = gravitational_parameter.sqrt();
+    let r1 = start_position.length();
+    let r2 = end_position.length();
+
+    let cos_delta_true_anomaly = (start_position.dot(end_position) / (r1 * r2)).clamp(-1.0, 1.0);
+    let direction_of_motion = if start_position.cross(end_position).dot(normal) >= 0.0 {
+        1.0
+    } else {
+        -1.0
+    };
+    let a = direction_of_motion * (r1 * r2 * (1.0 + cos_delta_true_anomaly)).sqrt();
+    if a.abs() < SOLVER_TOLERANCE * (r1 + r2) || time_of_flight <= 0.0 {
+        return None;
+    }
+
+    // using the universal variable formulation, the time of flight and y for a given psi, or
+    // `None` where psi is too low for y to be positive
+    let calculate_time = |psi: Scalar| -> Option<(Scalar, Scalar)> {
+        let c2 = calculate_stumpff_c2(psi);
+        let c3 = calculate_stumpff_c3(psi);
+        let y = r1 + r2 + a * (psi * c3 - 1.0) / c2.sqrt();
+        if y < 0.0 {
+            return None;
+        }
+        let chi = (y / c2).sqrt();
+        Some(((chi.powi(3) * c3 + a * y.sqrt()) / sqrt_mu, y))
+    };
+
+    // psi below zero gives hyperbolic trajectories, so it's lowered until they're fast enough,
+    // and it's kept below 4 PI^2 to limit the solution to less than one revolution
+    let mut psi_low = -4.0 * PI;
+    while let Some((time, _)) = calculate_time(psi_low) {
+        if !time.is_finite() {
+            return None;
+        }
+        if time < time_of_flight {
+            break;
+        }
+        psi_low *= 2.0;
+    }
+    let mut psi_high = 4.0 * PI * PI;
+
+    // the time of flight loses precision to cancellation on fast trajectories, so the bisection
+    // also stops once psi can't be narrowed any further
+    let mut solution = None;
+    for _i in 0..200 {
+        let psi = (psi_low + psi_high) / 2.0;
+        let Some((time, y)) = calculate_time(psi) else {
+            psi_low = psi;
+            continue;
+        };
+
+        if (time - time_of_flight).abs() <= SOLVER_TOLERANCE * time_of_flight
+            || psi_high - psi_low <= SOLVER_TOLERANCE * psi.abs().max(1.0)
+        {
+            solution = Some(y);
+            break;
+        }
+
+        if time < time_of_flight {
+            psi_low = psi;
+        } else {
+            psi_high = psi;
+        }
+    }
+    let y = solution?;
+
+    let f = 1.0 - y / r1;
+    let g = a * y.sqrt() / sqrt_mu;
+    let g_dot = 1.0 - y / r2;
+
+    let start_velocity = (end_position - f * start_position) / g;
+    let end_velocity = (g_dot * end_position - start_position) / g;
+    Some((start_velocity, end_velocity))
+}
+
+// Evenly spaced values from the start of the range to its end, inclusive.
+pub(crate) fn sample_range(range: RangeInclusive<Scalar>, steps: usize) -> Vec<Scalar> {
+    let (start, end) = range.into_inner();
+    match steps {
+        0 => Vec::new(),
+        1 => vec![start],
+        _ => (0..steps).map(|step| start + (end - start) * step as Scalar / (steps - 1) as Scalar).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orbit::Orbit;
+
+    const ECCENTRICITIES: [Scalar; 10] = [0.0, 0.1, 0.3, 0.5, 0.7, 0.9, 0.95, 0.99, 0.999, 0.9999];
+
+    // Kepler's equation can't be satisfied more closely than the precision of an angle.
+    const RESIDUAL_TOLERANCE: Scalar = SOLVER_TOLERANCE * TAU;
+
+    #[test]
+    fn eccentric_anomaly_converges_for_elliptic_eccentricities() {
+        for eccentricity in ECCENTRICITIES {
+            for step in 0..720 {
+                let mean_anomaly = TAU * step as Scalar / 720.0;
+                let solution = calculate_eccentric_anomaly_with_convergence(eccentricity, mean_anomaly);
+                assert!(
+                    solution.converged,
+                    "e = {eccentricity}, M = {mean_anomaly}: {solution:?}"
+                );
+                assert!(
+                    solution.residual <= RESIDUAL_TOLERANCE,
+                    "e = {eccentricity}, M = {mean_anomaly}: {solution:?}"
+                );
+                assert!(
+                    solution.iterations < 10,
+                    "e = {eccentricity}, M = {mean_anomaly}: {solution:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn eccentric_anomaly_near_periapsis_at_high_eccentricity() {
+        for eccentricity in [0.9, 0.99, 0.999, 0.9999] {
+            for mean_anomaly in [1e-6, 1e-4, 1e-2, TAU - 1e-2, TAU - 1e-4] {
+                let eccentric_anomaly = calculate_eccentric_anomaly(eccentricity, mean_anomaly);
+                assert!(
+                    (0.0..TAU).contains(&eccentric_anomaly),
+                    "e = {eccentricity}, M = {mean_anomaly}"
+                );
+
+                let recovered = calculate_mean_anomaly_from_eccentric_anomaly(eccentricity, eccentric_anomaly);
+                assert!(
+                    (recovered - mean_anomaly).abs() <= RESIDUAL_TOLERANCE,
+                    "e = {eccentricity}, M = {mean_anomaly}, recovered {recovered}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn eccentric_anomaly_of_circular_orbit_is_mean_anomaly() {
+        for step in 0..16 {
+            let mean_anomaly = TAU * step as Scalar / 16.0;
+            let eccentric_anomaly = calculate_eccentric_anomaly(0.0, mean_anomaly);
+            assert!((eccentric_anomaly - mean_anomaly).abs() <= RESIDUAL_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn true_anomaly_is_in_the_same_half_of_the_orbit_as_eccentric_anomaly() {
+        for eccentricity in ECCENTRICITIES {
+            for step in 1..360 {
+                let eccentric_anomaly = TAU * step as Scalar / 360.0;
+                let true_anomaly = calculate_true_anomaly(eccentricity, eccentric_anomaly);
+                assert!(
+                    (0.0..TAU).contains(&true_anomaly),
+                    "e = {eccentricity}, E = {eccentric_anomaly}"
+                );
+                assert_eq!(
+                    true_anomaly < PI,
+                    eccentric_anomaly < PI,
+                    "e = {eccentricity}, E = {eccentric_anomaly}, v = {true_anomaly}"
+                );
+
+                let recovered = calculate_eccentric_anomaly_from_true_anomaly(eccentricity, true_anomaly);
+                assert!(
+                    (recovered - eccentric_anomaly).abs() <= RESIDUAL_TOLERANCE,
+                    "e = {eccentricity}, E = {eccentric_anomaly}, recovered {recovered}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mean_anomaly_round_trips_through_signed_true_anomaly() {
+        for eccentricity in [0.0, 0.3, 0.7, 0.9] {
+            for step in 0..360 {
+                let mean_anomaly = TAU * step as Scalar / 360.0;
+                let eccentric_anomaly = calculate_eccentric_anomaly(eccentricity, mean_anomaly);
+                let true_anomaly = calculate_true_anomaly(eccentricity, eccentric_anomaly);
+                let signed_true_anomaly = if true_anomaly <= PI {
+                    true_anomaly
+                } else {
+                    true_anomaly - TAU
+                };
+
+                let recovered = calculate_mean_anomaly_from_true_anomaly(eccentricity, signed_true_anomaly);
+                let error = (recovered - mean_anomaly).rem_euclid(TAU);
+                assert!(
+                    error.min(TAU - error) <= RESIDUAL_TOLERANCE,
+                    "e = {eccentricity}, M = {mean_anomaly}, recovered {recovered}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn eccentric_anomaly_stays_in_the_same_turn() {
+        let eccentric_anomaly = calculate_eccentric_anomaly(0.5, 3.0 * TAU + 1.0);
+        let recovered = calculate_mean_anomaly_from_eccentric_anomaly(0.5, eccentric_anomaly - 3.0 * TAU);
+        assert!((3.0 * TAU..4.0 * TAU).contains(&eccentric_anomaly));
+        assert!((recovered - 1.0).abs() <= RESIDUAL_TOLERANCE);
+    }
+
+    // Checks the trajectory from the Lambert solver reaches the end position at the time of flight,
+    // with the end velocity, travelling prograde about the normal.
+    fn assert_lambert_round_trip(end_position: Vector, time_of_flight: Scalar, normal: Vector) {
+        let gravitational_parameter = 1000.0;
+        let start_position = Vector::new(10.0, 0.0, 0.0);
+        // relative to the distance from the parent, allowing for the precision lost propagating
+        // fast trajectories
+        let tolerance = SOLVER_TOLERANCE.sqrt();
+        let (start_velocity, end_velocity) = calculate_lambert_velocities(
+            start_position,
+            end_position,
+            time_of_flight,
+            normal,
+            gravitational_parameter,
+        )
+        .unwrap_or_else(|| panic!("no solution to {end_position} in {time_of_flight}"));
+
+        let orbit = Orbit::from_state_vectors(start_position, start_velocity, gravitational_parameter, 0.0);
+        let (position, velocity) = orbit.state_at(time_of_flight, gravitational_parameter);
+        assert!(
+            (position - end_position).length() <= tolerance * end_position.length(),
+            "{end_position} in {time_of_flight}: reached {position} on {orbit:?}"
+        );
+        assert!(
+            (velocity - end_velocity).length() <= tolerance * end_velocity.length(),
+            "{end_position} in {time_of_flight}: velocity {velocity}, expected {end_velocity}"
+        );
+        assert!(start_position.cross(start_velocity).dot(normal) > 0.0);
+    }
+
+    #[test]
+    fn lambert_trajectory_reaches_end_position() {
+        for time_of_flight in [0.9, 5.0, 20.0] {
+            for degrees in (2..360_i32).step_by(6).filter(|degrees| (degrees - 180).abs() > 4) {
+                let angle = (degrees as Scalar).to_radians();
+                let end_position = Vector::new(angle.cos(), 0.0, -angle.sin()) * 20.0;
+                assert_lambert_round_trip(end_position, time_of_flight, Vector::Y);
+            }
+        }
+    }
+
+    #[test]
+    fn lambert_trajectory_travels_retrograde_about_normal() {
+        for time_of_flight in [0.9, 5.0, 20.0] {
+            for degrees in (2..360_i32).step_by(6).filter(|degrees| (degrees - 180).abs() > 4) {
+                let angle = (degrees as Scalar).to_radians();
+                let end_position = Vector::new(angle.cos(), 0.0, -angle.sin()) * 20.0;
+                assert_lambert_round_trip(end_position, time_of_flight, Vector::NEG_Y);
+            }
+        }
+    }
+
+    #[test]
+    fn lambert_trajectory_between_inclined_positions() {
+        for time_of_flight in [0.9, 5.0] {
+            let end_position = Vector::new(-5.0, 12.0, -14.0);
+            let normal = Vector::new(10.0, 0.0, 0.0).cross(end_position);
+            assert_lambert_round_trip(end_position, time_of_flight, normal);
+        }
+    }
+
+    #[test]
+    fn parabola_accepts_negative_periapsis_distance() {
+        for time in [-10.0, 0.0, 10.0] {
+            let position = Vector::from(calculate_position_at_time(-10.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1000.0, time));
+            let expected = Vector::from(calculate_position_at_time(10.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1000.0, time));
+            assert_eq!(position, expected, "t = {time}");
+        }
+    }
+
+    #[test]
+    fn periapsis_distance_is_continuous_through_parabola() {
+        let periapsis_distance = 10.0;
+        for eccentricity in [0.9999, 0.9999995, 1.0, 1.0000005, 1.0001] {
+            let semi_major_axis = if eccentricity == 1.0 {
+                periapsis_distance
+            } else {
+                periapsis_distance / (1.0 - eccentricity)
+            };
+            let position = Vector::from(calculate_position_at_time(
+                semi_major_axis,
+                eccentricity,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                1000.0,
+                0.0,
+            ));
+            assert!(
+                (position.length() - periapsis_distance).abs() <= 1e-3 * periapsis_distance,
+                "e = {eccentricity}: periapsis at {}",
+                position.length()
+            );
+        }
+    }
 }
@@ -1,21 +1,352 @@
 use bevy::prelude::*;
 
-#[derive(Component, Clone, Debug)]
+use crate::math::consts::TAU;
+use crate::math::*;
+use crate::time::OrbitalTime;
+
+#[derive(Component, Clone, Debug, PartialEq)]
 pub struct Orbit {
-    pub semi_major_axis: f32,
-    pub eccentricity: f32,
-    pub argument_of_periapsis: f32,
-    pub initial_mean_anomaly: f32,
+    // Negative for hyperbolic trajectories (a positive value is also accepted), and the
+    // periapsis distance for parabolic trajectories with an eccentricity of exactly 1.
+    pub semi_major_axis: Scalar,
+    pub eccentricity: Scalar,
+    pub argument_of_periapsis: Scalar,
+    pub inclination: Scalar,
+    pub longitude_of_ascending_node: Scalar,
+    pub initial_mean_anomaly: Scalar,
+}
+
+impl Orbit {
+    // Builds the orbit of a body with the given position and velocity relative to its parent
+    // at the given time.
+    pub fn from_state_vectors(
+        position: Vector,
+        velocity: Vector,
+        gravitational_parameter: Scalar,
+        time: Scalar,
+    ) -> Orbit {
+        let distance = position.length();
+        let angular_momentum = position.cross(velocity);
+        let normal = angular_momentum.normalize();
+        let eccentricity_vector = velocity.cross(angular_momentum) / gravitational_parameter - position / distance;
+        let eccentricity = eccentricity_vector.length();
+        let conic = Conic::from_eccentricity(eccentricity);
+
+        // the semi-major axis of a parabola is infinite, so it's given an eccentricity of exactly 1
+        // and the periapsis distance instead
+        let (semi_major_axis, conic_eccentricity) = match conic {
+            Conic::Parabola => (angular_momentum.length_squared() / (2.0 * gravitational_parameter), 1.0),
+            _ => (
+                -gravitational_parameter / (velocity.length_squared() - 2.0 * gravitational_parameter / distance),
+                eccentricity,
+            ),
+        };
+
+        let node = Vector::new(angular_momentum.z, 0.0, -angular_momentum.x);
+        let inclination = node.length().atan2(angular_momentum.y);
+        let longitude_of_ascending_node = if node.length() < ORBIT_TOLERANCE * angular_momentum.length() {
+            0.0
+        } else {
+            node.z.atan2(node.x).rem_euclid(TAU)
+        };
+
+        let node_direction = Vector::new(
+            longitude_of_ascending_node.cos(),
+            0.0,
+            longitude_of_ascending_node.sin(),
+        );
+        let node_normal = node_direction.cross(normal);
+        let periapsis_direction = if eccentricity < ORBIT_TOLERANCE {
+            node_direction
+        } else {
+            eccentricity_vector / eccentricity
+        };
+        let argument_of_periapsis =
+            periapsis_direction.dot(node_normal).atan2(periapsis_direction.dot(node_direction)).rem_euclid(TAU);
+
+        let radial_direction = position / distance;
+        let true_anomaly =
+            periapsis_direction.cross(radial_direction).dot(normal).atan2(periapsis_direction.dot(radial_direction));
+
+        let orbit = Orbit {
+            semi_major_axis,
+            eccentricity: conic_eccentricity,
+            argument_of_periapsis,
+            inclination,
+            longitude_of_ascending_node,
+            initial_mean_anomaly: 0.0,
+        };
+        orbit.with_true_anomaly_at(true_anomaly, time, gravitational_parameter)
+    }
+
+    // Position and velocity relative to the parent at the given time.
+    pub fn state_at(&self, time: Scalar, gravitational_parameter: Scalar) -> (Vector, Vector) {
+        let true_anomaly = self.true_anomaly_at(time, gravitational_parameter);
+        let semilatus_rectum = calculate_semilatus_rectum(self.semi_major_axis, self.eccentricity);
+        let distance = semilatus_rectum / (1.0 + self.eccentricity * true_anomaly.cos());
+
+        let position = calculate_position(
+            true_anomaly,
+            distance,
+            self.argument_of_periapsis,
+            self.inclination,
+            self.longitude_of_ascending_node,
+        );
+        let velocity = calculate_velocity(
+            true_anomaly,
+            semilatus_rectum,
+            self.eccentricity,
+            self.argument_of_periapsis,
+            self.inclination,
+            self.longitude_of_ascending_node,
+            gravitational_parameter,
+        );
+
+        (Vector::from(position), Vector::from(velocity))
+    }
+
+    // The same orbit, with the body placed at the given true anomaly at the given time.
+    pub fn with_true_anomaly_at(&self, true_anomaly: Scalar, time: Scalar, gravitational_parameter: Scalar) -> Orbit {
+        let mean_anomaly = calculate_mean_anomaly_from_true_anomaly(self.eccentricity, true_anomaly);
+        let initial_mean_anomaly = mean_anomaly - self.mean_motion(gravitational_parameter) * time;
+        Orbit {
+            initial_mean_anomaly: match self.conic() {
+                Conic::Ellipse => initial_mean_anomaly.rem_euclid(TAU),
+                _ => initial_mean_anomaly,
+            },
+            ..self.clone()
+        }
+    }
+
+    // This orbit, with the body placed at the true anomaly it had on the previous orbit at the
+    // given time, so it doesn't jump along its path when the orbit or its parent's mass changes.
+    pub fn with_true_anomaly_of(
+        &self,
+        previous_orbit: &Orbit,
+        previous_gravitational_parameter: Scalar,
+        gravitational_parameter: Scalar,
+        time: Scalar,
+    ) -> Orbit {
+        let true_anomaly = previous_orbit.true_anomaly_at(time, previous_gravitational_parameter);
+        self.with_true_anomaly_at(true_anomaly, time, gravitational_parameter)
+    }
+
+    pub fn true_anomaly_at(&self, time: Scalar, gravitational_parameter: Scalar) -> Scalar {
+        calculate_true_anomaly_at_time(
+            self.semi_major_axis,
+            self.eccentricity,
+            self.initial_mean_anomaly,
+            gravitational_parameter,
+            time,
+        )
+    }
+
+    // The first time at or after `after` that the body reaches the given true anomaly. Open
+    // trajectories only reach each true anomaly once, which may be before `after`.
+    pub fn time_of_true_anomaly(&self, true_anomaly: Scalar, after: Scalar, gravitational_parameter: Scalar) -> Scalar {
+        let mean_motion = self.mean_motion(gravitational_parameter);
+        let mean_anomaly = calculate_mean_anomaly_from_true_anomaly(self.eccentricity, true_anomaly);
+        match self.conic() {
+            Conic::Ellipse => {
+                let current_mean_anomaly = calculate_mean_anomaly(mean_motion, self.initial_mean_anomaly, after);
+                after + (mean_anomaly - current_mean_anomaly).rem_euclid(TAU) / mean_motion
+            }
+            _ => (mean_anomaly - self.initial_mean_anomaly) / mean_motion,
+        }
+    }
+
+    // Unit vectors towards periapsis, towards a true anomaly of 90 degrees, and along the
+    // orbit normal.
+    pub fn perifocal_axes(&self) -> (Vector, Vector, Vector) {
+        let periapsis = Vector::from(rotate_from_orbital_plane(
+            1.0,
+            0.0,
+            self.argument_of_periapsis,
+            self.inclination,
+            self.longitude_of_ascending_node,
+        ));
+        let quarter = Vector::from(rotate_from_orbital_plane(
+            0.0,
+            -1.0,
+            self.argument_of_periapsis,
+            self.inclination,
+            self.longitude_of_ascending_node,
+        ));
+        (periapsis, quarter, periapsis.cross(quarter))
+    }
+
+    // True anomaly at which the body passes through the given direction, projected onto the
+    // orbital plane.
+    pub fn true_anomaly_of_direction(&self, direction: Vector) -> Scalar {
+        let (periapsis, quarter, _) = self.perifocal_axes();
+        direction.dot(quarter).atan2(direction.dot(periapsis))
+    }
+
+    // Angle from this body to the body on the other orbit, measured around this orbit's normal in
+    // the direction of motion, between -PI and PI.
+    pub fn phase_angle_to(&self, other: &Orbit, time: Scalar, gravitational_parameter: Scalar) -> Scalar {
+        let (position, _) = self.state_at(time, gravitational_parameter);
+        let (other_position, _) = other.state_at(time, gravitational_parameter);
+        let (_, _, normal) = self.perifocal_axes();
+        position.cross(other_position).dot(normal).atan2(position.dot(other_position))
+    }
+
+    // Phase angle the body on the target orbit needs when a Hohmann transfer to it departs.
+    pub fn hohmann_phase_angle_to(&self, target: &Orbit, gravitational_parameter: Scalar) -> Scalar {
+        calculate_hohmann_phase_angle(self.semi_major_axis, target.semi_major_axis, gravitational_parameter)
+    }
+
+    // The first time at or after `after` that a Hohmann transfer to the target orbit can depart
+    // and arrive alongside the body on it, for circular coplanar orbits. Infinite if both orbits
+    // have the same period.
+    pub fn next_hohmann_departure(&self, target: &Orbit, after: Scalar, gravitational_parameter: Scalar) -> Scalar {
+        let phase_angle = self.phase_angle_to(target, after, gravitational_parameter);
+        let relative_mean_motion =
+            target.mean_motion(gravitational_parameter) - self.mean_motion(gravitational_parameter);
+        after
+            + calculate_time_to_phase_angle(
+                phase_angle,
+                self.hohmann_phase_angle_to(target, gravitational_parameter),
+                relative_mean_motion,
+            )
+    }
+
+    pub fn mean_motion(&self, gravitational_parameter: Scalar) -> Scalar {
+        calculate_conic_mean_motion(self.semi_major_axis, self.eccentricity, gravitational_parameter)
+    }
+
+    pub fn conic(&self) -> Conic {
+        Conic::from_eccentricity(self.eccentricity)
+    }
+}
+
+// The mass of a body that others orbit, or its gravitational parameter (often called mu), the
+// mass multiplied by the gravitational constant, which is known more precisely for most bodies.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub enum Mass {
+    Mass(Scalar),
+    GravitationalParameter(Scalar),
+}
+
+impl Mass {
+    pub fn gravitational_parameter(&self, gravitational_constant: &GravitationalConstant) -> Scalar {
+        match *self {
+            Mass::Mass(mass) => calculate_gravitational_parameter(gravitational_constant.0, mass),
+            Mass::GravitationalParameter(gravitational_parameter) => gravitational_parameter,
+        }
+    }
+
+    // Radius of this body's sphere of influence, when it's on the given orbit around a parent.
+    pub fn sphere_of_influence(
+        &self,
+        orbit: &Orbit,
+        parent_mass: &Mass,
+        gravitational_constant: &GravitationalConstant,
+    ) -> Scalar {
+        calculate_sphere_of_influence(
+            orbit.semi_major_axis,
+            self.gravitational_parameter(gravitational_constant),
+            parent_mass.gravitational_parameter(gravitational_constant),
+        )
+    }
+}
+
+// Multiplies a `Mass::Mass` to give its gravitational parameter, set by `OrbitPlugin`. Defaults
+// to the gravitational constant in SI units.
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct GravitationalConstant(pub Scalar);
+
+impl Default for GravitationalConstant {
+    fn default() -> Self {
+        GravitationalConstant(GRAVITATIONAL_CONSTANT)
+    }
+}
+
+// How distances in the units of `Orbit::semi_major_axis` are converted to world units when
+// orbits are written to `Transform::translation`, set by `OrbitPlugin`. `OrbitalVelocity` is
+// left in orbit units.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct OrbitScale {
+    pub distance_per_world_unit: Scalar,
+    // Overrides `distance_per_world_unit` for each depth of the hierarchy, starting with bodies
+    // orbiting a root entity, so moons can be spread further from their planets than the planets
+    // are from the sun. Deeper orbits use `distance_per_world_unit`.
+    pub level_distances_per_world_unit: Vec<Scalar>,
+}
+
+impl OrbitScale {
+    pub fn distance_per_world_unit_at(&self, depth: usize) -> Scalar {
+        self.level_distances_per_world_unit.get(depth).copied().unwrap_or(self.distance_per_world_unit)
+    }
+}
+
+impl Default for OrbitScale {
+    fn default() -> Self {
+        OrbitScale {
+            distance_per_world_unit: 1.0,
+            level_distances_per_world_unit: Vec::new(),
+        }
+    }
+}
+
+// Keeps the body at the same true anomaly when its orbit or its parent's mass is changed without
+// changing the initial mean anomaly, instead of letting it jump along its path. Orbits changed by
+// maneuvers or by moving between spheres of influence are left alone.
+#[derive(Component, Default)]
+pub struct PreserveTrueAnomaly {
+    previous: Option<(Orbit, Scalar)>,
+}
+
+impl PreserveTrueAnomaly {
+    // Leaves this frame's change to the orbit or parent as it is, for code that moves the body
+    // onto a new orbit itself, such as a maneuver.
+    pub fn ignore_change(&mut self) {
+        self.previous = None;
+    }
 }
 
-#[derive(Component)]
-pub struct Mass {
-    pub mass: f32,
+pub fn preserve_true_anomalies(
+    time: Res<OrbitalTime>,
+    gravitational_constant: Res<GravitationalConstant>,
+    masses: Query<Ref<Mass>>,
+    mut orbits: Query<(Mut<Orbit>, &mut PreserveTrueAnomaly, Option<&Parent>)>,
+) {
+    for (mut orbit, mut preserve, maybe_parent) in orbits.iter_mut() {
+        let Some(parent_mass) = maybe_parent.and_then(|parent| masses.get(parent.get()).ok()) else {
+            preserve.previous = None;
+            continue;
+        };
+        let changed = orbit.is_changed() || parent_mass.is_changed() || gravitational_constant.is_changed();
+        if !changed && preserve.previous.is_some() {
+            continue;
+        }
+        let gravitational_parameter = parent_mass.gravitational_parameter(&gravitational_constant);
+
+        if let Some((previous_orbit, previous_gravitational_parameter)) = &preserve.previous {
+            let edited = previous_orbit != &*orbit || *previous_gravitational_parameter != gravitational_parameter;
+            if edited && previous_orbit.initial_mean_anomaly == orbit.initial_mean_anomaly {
+                let rebased = orbit.with_true_anomaly_of(
+                    previous_orbit,
+                    *previous_gravitational_parameter,
+                    gravitational_parameter,
+                    time.seconds(),
+                );
+                if rebased.initial_mean_anomaly.is_finite() {
+                    *orbit = rebased;
+                }
+            }
+        }
+
+        preserve.previous = Some((orbit.clone(), gravitational_parameter));
+    }
 }
 
 pub fn calculate_orbits(
-    time: Res<Time>,
+    time: Res<OrbitalTime>,
+    gravitational_constant: Res<GravitationalConstant>,
+    scale: Res<OrbitScale>,
     masses: Query<&Mass>,
+    parents: Query<&Parent>,
     mut orbits: Query<(&Orbit, &mut Transform, Option<&Parent>)>,
 ) {
     for (orbit, mut transform, maybe_parent) in orbits.iter_mut() {
@@ -24,6 +355,14 @@ pub fn calculate_orbits(
             continue;
         }
 
+        if orbit.semi_major_axis < 0.0 && orbit.conic() == Conic::Ellipse {
+            warn!(
+                "Elliptic orbit has a negative semi-major axis {}",
+                orbit.semi_major_axis
+            );
+            continue;
+        }
+
         let Some(parent) = maybe_parent else {
             transform.translation = Vec3::ZERO;
             continue;
@@ -34,14 +373,119 @@ pub fn calculate_orbits(
             continue;
         };
 
-        let pos = crate::math::calculate_position_at_time(
+        let pos = calculate_position_at_time(
             orbit.semi_major_axis,
             orbit.eccentricity,
             orbit.argument_of_periapsis,
+            orbit.inclination,
+            orbit.longitude_of_ascending_node,
             orbit.initial_mean_anomaly,
-            parent_mass.mass,
-            time.elapsed_seconds(),
+            parent_mass.gravitational_parameter(&gravitational_constant),
+            time.seconds(),
+        );
+        let position = Vector::from(pos);
+        if !position.is_finite() {
+            warn!("Orbit {orbit:?} has no finite position");
+            continue;
+        }
+
+        let mut depth = 0;
+        let mut ancestor = parent.get();
+        while let Ok(grandparent) = parents.get(ancestor) {
+            depth += 1;
+            ancestor = grandparent.get();
+        }
+        transform.translation = as_vec3(position / scale.distance_per_world_unit_at(depth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAVITATIONAL_PARAMETER: Scalar = 1000.0;
+
+    // Relative to the size of the position and velocity.
+    const TOLERANCE: Scalar = 1e3 * Scalar::EPSILON;
+
+    fn assert_state_round_trip(position: Vector, velocity: Vector, conic: Conic) {
+        for time in [0.0, 3.0, -7.5] {
+            let orbit = Orbit::from_state_vectors(position, velocity, GRAVITATIONAL_PARAMETER, time);
+            assert_eq!(orbit.conic(), conic, "{orbit:?}");
+
+            let (recovered_position, recovered_velocity) = orbit.state_at(time, GRAVITATIONAL_PARAMETER);
+            assert!(
+                (recovered_position - position).length() <= TOLERANCE * position.length(),
+                "{position} at {time}: recovered {recovered_position} from {orbit:?}"
+            );
+            assert!(
+                (recovered_velocity - velocity).length() <= TOLERANCE * velocity.length(),
+                "{velocity} at {time}: recovered {recovered_velocity} from {orbit:?}"
+            );
+        }
+    }
+
+    // Speed of a circular orbit at the given distance.
+    fn circular_speed(distance: Scalar) -> Scalar {
+        (GRAVITATIONAL_PARAMETER / distance).sqrt()
+    }
+
+    #[test]
+    fn elliptic_state_round_trips() {
+        let position = Vector::new(10.0, 0.0, 0.0);
+        let velocity = Vector::new(2.0, 0.0, -11.0);
+        assert_state_round_trip(position, velocity, Conic::Ellipse);
+        assert_state_round_trip(-position, -velocity, Conic::Ellipse);
+    }
+
+    #[test]
+    fn hyperbolic_state_round_trips() {
+        let position = Vector::new(10.0, 0.0, 0.0);
+        let velocity = Vector::new(-3.0, 0.0, -15.0);
+        assert_state_round_trip(position, velocity, Conic::Hyperbola);
+        assert_state_round_trip(position, -velocity, Conic::Hyperbola);
+    }
+
+    #[test]
+    fn parabolic_state_round_trips() {
+        let position = Vector::new(10.0, 0.0, 0.0);
+        let escape_speed = (2.0 as Scalar).sqrt() * circular_speed(10.0);
+        assert_state_round_trip(position, Vector::new(0.0, 0.0, -escape_speed), Conic::Parabola);
+        let direction = Vector::new(0.6, 0.0, -0.8);
+        assert_state_round_trip(position, direction * escape_speed, Conic::Parabola);
+    }
+
+    #[test]
+    fn inclined_state_round_trips() {
+        let position = Vector::new(6.0, 4.0, -5.0);
+        assert_state_round_trip(position, Vector::new(-2.0, 5.0, -8.0), Conic::Ellipse);
+        assert_state_round_trip(position, Vector::new(-2.0, 12.0, -14.0), Conic::Hyperbola);
+    }
+
+    #[test]
+    fn retrograde_state_round_trips() {
+        let position = Vector::new(10.0, 0.0, 0.0);
+        assert_state_round_trip(position, Vector::new(1.0, 0.0, 9.0), Conic::Ellipse);
+        assert_state_round_trip(position, Vector::new(1.0, 2.0, 9.0), Conic::Ellipse);
+    }
+
+    #[test]
+    fn circular_equatorial_state_round_trips() {
+        let speed = circular_speed(10.0);
+        assert_state_round_trip(
+            Vector::new(10.0, 0.0, 0.0),
+            Vector::new(0.0, 0.0, -speed),
+            Conic::Ellipse,
+        );
+        assert_state_round_trip(
+            Vector::new(0.0, 0.0, 10.0),
+            Vector::new(speed, 0.0, 0.0),
+            Conic::Ellipse,
+        );
+        assert_state_round_trip(
+            Vector::new(-6.0, 0.0, 8.0),
+            Vector::new(-0.8, 0.0, -0.6) * speed,
+            Conic::Ellipse,
         );
-        transform.translation = Vec3::from(pos);
     }
 }
@@ -0,0 +1,87 @@
+use crate::math::*;
+use crate::orbit::Orbit;
+use crate::transfer::{
+    calculate_half_orbit_transfer, calculate_hohmann_transfer, cheapest_transfer, Maneuver, Transfer, TransferError,
+};
+
+// Single burn transfer that rotates the orbit into the plane with the given inclination and
+// longitude of the ascending node, keeping its shape. The burn is made where the orbit crosses
+// the new plane, at whichever of the two crossings after `execution_time` is cheaper.
+pub fn calculate_plane_change_transfer(
+    orbit: &Orbit,
+    inclination: Scalar,
+    longitude_of_ascending_node: Scalar,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let (_, _, normal) = orbit.perifocal_axes();
+    let target_plane = Orbit {
+        inclination,
+        longitude_of_ascending_node,
+        ..orbit.clone()
+    };
+    let (_, _, target_normal) = target_plane.perifocal_axes();
+    let line_of_nodes = normal.cross(target_normal);
+    if line_of_nodes.length() < ORBIT_TOLERANCE {
+        return Err(TransferError::NoSolution);
+    }
+
+    let transfers = [line_of_nodes.normalize(), -line_of_nodes.normalize()].into_iter().map(|node_direction| {
+        let node_true_anomaly = orbit.true_anomaly_of_direction(node_direction);
+        let node_time = orbit.time_of_true_anomaly(node_true_anomaly, execution_time, gravitational_parameter);
+        let (position, velocity) = orbit.state_at(node_time, gravitational_parameter);
+
+        // rotate about the line of nodes, which carries the orbit normal onto the target normal
+        let axis = position.normalize();
+        let angle = normal.cross(target_normal).dot(axis).atan2(normal.dot(target_normal));
+        let rotated_velocity = rotate_about_axis(velocity, axis, angle);
+
+        let maneuver = Maneuver {
+            start_orbit: orbit.clone(),
+            target_orbit: Orbit::from_state_vectors(position, rotated_velocity, gravitational_parameter, node_time),
+            execution_time: node_time,
+            parent_change: None,
+        };
+        Transfer {
+            maneuvers: vec![maneuver].into(),
+        }
+    });
+    cheapest_transfer(transfers, gravitational_parameter)
+}
+
+// Two burn transfer between orbits in different planes, departing and arriving where the planes
+// intersect. The change of plane is combined with whichever burn is made at the transfer's
+// apoapsis, where the body is slowest and turning it costs the least.
+pub fn calculate_combined_plane_change_transfer(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if start_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let (_, _, start_normal) = start_orbit.perifocal_axes();
+    let (_, _, target_normal) = target_orbit.perifocal_axes();
+    let line_of_nodes = start_normal.cross(target_normal);
+    if line_of_nodes.length() < ORBIT_TOLERANCE {
+        return calculate_hohmann_transfer(start_orbit, target_orbit, gravitational_parameter, execution_time);
+    }
+
+    let transfers = [line_of_nodes.normalize(), -line_of_nodes.normalize()].into_iter().map(|departure_direction| {
+        calculate_half_orbit_transfer(
+            start_orbit,
+            target_orbit,
+            departure_direction,
+            true,
+            gravitational_parameter,
+            execution_time,
+        )
+    });
+    cheapest_transfer(transfers, gravitational_parameter)
+}
@@ -1,17 +1,46 @@
 use bevy::prelude::*;
 
-use crate::orbit::calculate_orbits;
-use crate::transfer::execute_orbital_maneuvers;
+use crate::math::{Scalar, GRAVITATIONAL_CONSTANT};
+use crate::orbit::{calculate_orbits, preserve_true_anomalies, GravitationalConstant, OrbitScale};
+use crate::soi::{transition_spheres_of_influence, SphereOfInfluenceChanged};
+use crate::time::{update_orbital_time, OrbitalTime};
+use crate::transfer::{execute_orbital_maneuvers, ManeuverExecuted, TransferCompleted, TransferStarted};
+use crate::velocity::calculate_orbital_velocities;
 
-pub struct OrbitPlugin;
+pub struct OrbitPlugin {
+    // Multiplies each `Mass::Mass` to give its gravitational parameter, for simulations using
+    // units other than SI.
+    pub gravitational_constant: Scalar,
+    pub scale: OrbitScale,
+}
+
+impl Default for OrbitPlugin {
+    fn default() -> Self {
+        OrbitPlugin {
+            gravitational_constant: GRAVITATIONAL_CONSTANT,
+            scale: OrbitScale::default(),
+        }
+    }
+}
 
 impl Plugin for OrbitPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<OrbitalTime>()
+            .insert_resource(GravitationalConstant(self.gravitational_constant))
+            .insert_resource(self.scale.clone())
+            .add_event::<ManeuverExecuted>()
+            .add_event::<TransferStarted>()
+            .add_event::<TransferCompleted>()
+            .add_event::<SphereOfInfluenceChanged>();
+        app.add_systems(First, update_orbital_time.after(bevy::time::TimeSystem));
         app.add_systems(
             PostUpdate,
             (
                 execute_orbital_maneuvers.before(bevy::transform::systems::propagate_transforms),
-                calculate_orbits.after(execute_orbital_maneuvers),
+                transition_spheres_of_influence.after(execute_orbital_maneuvers),
+                preserve_true_anomalies.after(transition_spheres_of_influence),
+                calculate_orbits.after(preserve_true_anomalies),
+                calculate_orbital_velocities.after(bevy::transform::TransformSystem::TransformPropagate),
             ),
         );
     }
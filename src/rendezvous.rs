@@ -0,0 +1,127 @@
+use crate::math::consts::{PI, TAU};
+use crate::math::*;
+use crate::orbit::Orbit;
+use crate::transfer::{calculate_hohmann_transfer, calculate_tangential_burn, Maneuver, Transfer, TransferError};
+use crate::window::calculate_transfer_window;
+
+// Plans a transfer that meets the body on the target orbit, rather than just reaching its orbit.
+// On the same orbit this is a phasing transfer, between circular coplanar orbits it's a Hohmann
+// transfer that waits for the right phase angle, and otherwise it's the transfer found by
+// `calculate_transfer_window`.
+pub fn calculate_rendezvous_transfer(
+    chaser_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if chaser_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let (chaser_periapsis, _, chaser_normal) = chaser_orbit.perifocal_axes();
+    let (target_periapsis, _, target_normal) = target_orbit.perifocal_axes();
+    let circular = chaser_orbit.eccentricity < ORBIT_TOLERANCE && target_orbit.eccentricity < ORBIT_TOLERANCE;
+    let coplanar = chaser_normal.dot(target_normal) > 1.0 - ORBIT_TOLERANCE;
+    let relative_mean_motion =
+        target_orbit.mean_motion(gravitational_parameter) - chaser_orbit.mean_motion(gravitational_parameter);
+
+    let same_orbit = coplanar
+        && (chaser_orbit.semi_major_axis - target_orbit.semi_major_axis).abs()
+            < ORBIT_TOLERANCE * chaser_orbit.semi_major_axis
+        && (chaser_orbit.eccentricity - target_orbit.eccentricity).abs() < ORBIT_TOLERANCE
+        && (circular || chaser_periapsis.dot(target_periapsis) > 1.0 - ORBIT_TOLERANCE);
+
+    if same_orbit {
+        let (target_position, _) = target_orbit.state_at(execution_time, gravitational_parameter);
+        let target_true_anomaly = chaser_orbit.true_anomaly_of_direction(target_position);
+        let chaser_true_anomaly = chaser_orbit.true_anomaly_at(execution_time, gravitational_parameter);
+        let phase_offset = calculate_mean_anomaly_from_true_anomaly(chaser_orbit.eccentricity, target_true_anomaly)
+            - calculate_mean_anomaly_from_true_anomaly(chaser_orbit.eccentricity, chaser_true_anomaly);
+        calculate_phasing_transfer(
+            chaser_orbit,
+            (phase_offset + PI).rem_euclid(TAU) - PI,
+            1,
+            gravitational_parameter,
+            execution_time,
+        )
+    } else if circular && coplanar && relative_mean_motion != 0.0 {
+        calculate_phased_hohmann_transfer(chaser_orbit, target_orbit, gravitational_parameter, execution_time)
+    } else {
+        calculate_transfer_window(chaser_orbit, target_orbit, gravitational_parameter, execution_time)?.transfer(
+            chaser_orbit,
+            target_orbit,
+            gravitational_parameter,
+        )
+    }
+}
+
+// Two burn transfer that moves a body `phase_offset` radians of mean anomaly ahead along its own
+// orbit (or behind, if negative), by spending `revolutions` revolutions on a slower or faster
+// phasing orbit. Both burns happen at periapsis, or straight away for circular orbits.
+pub fn calculate_phasing_transfer(
+    orbit: &Orbit,
+    phase_offset: Scalar,
+    revolutions: u32,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+    if revolutions == 0 {
+        return Err(TransferError::NoSolution);
+    }
+
+    let departure_time = if orbit.eccentricity < ORBIT_TOLERANCE {
+        execution_time
+    } else {
+        orbit.time_of_true_anomaly(0.0, execution_time, gravitational_parameter)
+    };
+    let (departure_position, _) = orbit.state_at(departure_time, gravitational_parameter);
+    let departure_radius = departure_position.length();
+
+    let period = calculate_period(orbit.semi_major_axis, gravitational_parameter);
+    let phasing_period = period - phase_offset / orbit.mean_motion(gravitational_parameter) / revolutions as Scalar;
+    let phasing_semi_major_axis = calculate_semi_major_axis(phasing_period, gravitational_parameter);
+    let phasing_speed = calculate_vis_viva_speed(departure_radius, phasing_semi_major_axis, gravitational_parameter);
+    if !phasing_speed.is_finite() || phasing_period <= 0.0 {
+        return Err(TransferError::NoSolution);
+    }
+    let phasing_orbit = calculate_tangential_burn(orbit, phasing_speed, gravitational_parameter, departure_time);
+
+    let phased_orbit = Orbit {
+        initial_mean_anomaly: (orbit.initial_mean_anomaly + phase_offset).rem_euclid(TAU),
+        ..orbit.clone()
+    };
+
+    let maneuver_1 = Maneuver {
+        start_orbit: orbit.clone(),
+        target_orbit: phasing_orbit.clone(),
+        execution_time: departure_time,
+        parent_change: None,
+    };
+    let maneuver_2 = Maneuver {
+        start_orbit: phasing_orbit,
+        target_orbit: phased_orbit,
+        execution_time: departure_time + phasing_period * revolutions as Scalar,
+        parent_change: None,
+    };
+
+    Ok(Transfer {
+        maneuvers: vec![maneuver_1, maneuver_2].into(),
+    })
+}
+
+fn calculate_phased_hohmann_transfer(
+    chaser_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    let departure_time = chaser_orbit.next_hohmann_departure(target_orbit, execution_time, gravitational_parameter);
+    let mut transfer = calculate_hohmann_transfer(chaser_orbit, target_orbit, gravitational_parameter, departure_time)?;
+    if let Some(arrival) = transfer.maneuvers.back_mut() {
+        arrival.target_orbit = target_orbit.clone();
+    }
+    Ok(transfer)
+}
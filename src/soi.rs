@@ -0,0 +1,567 @@
+use bevy::prelude::*;
+
+use crate::math::*;
+use crate::orbit::{GravitationalConstant, Mass, Orbit, PreserveTrueAnomaly};
+use crate::time::OrbitalTime;
+use crate::transfer::TransferSchedule;
+
+// Most steps taken along a trajectory in one frame while looking for a sphere of influence
+// crossing, which sets the smallest step.
+const SEARCH_STEPS: usize = 1024;
+// Most sphere of influence changes for one entity in one frame.
+const MAX_TRANSITIONS: usize = 8;
+const BISECTION_ITERATIONS: u32 = 64;
+// Fraction of a sphere of influence's radius a body has to go past its edge to cross it, so an
+// orbit patched at a crossing isn't sent straight back by rounding errors.
+const CROSSING_MARGIN: Scalar = 1e3 * Scalar::EPSILON;
+
+// Marks an orbiting entity that moves between spheres of influence, changing parent when its
+// trajectory leaves its parent's sphere of influence or enters the sphere of influence of a
+// sibling. The crossing is found along the path travelled since the last frame, and the new orbit
+// starts at the crossing time.
+//
+// Transitions are kept in `history`, and undone if `OrbitalTime` goes backwards. No
+// `SphereOfInfluenceChanged` event is sent when one is undone.
+//
+// Only the orbit the entity is on after its maneuvers is searched: when a maneuver in its
+// `TransferSchedule` executes late, the path between the last check and the burn is skipped, so a
+// crossing just before the burn is missed. Maneuvers that cross a sphere of influence should
+// change parent with `Maneuver::parent_change` instead.
+#[derive(Component, Default, Debug, Clone)]
+pub struct PatchedConic {
+    pub history: Vec<SphereOfInfluenceTransition>,
+    // How far along its trajectory the entity has been checked for crossings.
+    checked_until: Option<Scalar>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SphereOfInfluenceTransition {
+    pub time: Scalar,
+    // The orbit and parent before the transition, restored if it's undone.
+    pub previous_orbit: Orbit,
+    pub previous_parent: Entity,
+    pub parent: Entity,
+}
+
+impl PatchedConic {
+    // Pops the last transition if time has gone back past it, unless a later maneuver has to be
+    // undone first.
+    pub(crate) fn reverted_transition(
+        &mut self,
+        seconds: Scalar,
+        last_maneuver_time: Option<Scalar>,
+    ) -> Option<SphereOfInfluenceTransition> {
+        let last_transition = self.history.last()?;
+
+        if seconds >= last_transition.time || last_maneuver_time.is_some_and(|time| time > last_transition.time) {
+            return None;
+        }
+
+        self.checked_until = Some(seconds);
+        self.history.pop()
+    }
+
+    // The orbit changed at the given time, so the path before it isn't checked, even if it hadn't
+    // been yet.
+    pub(crate) fn restart_at(&mut self, seconds: Scalar) {
+        self.checked_until = Some(seconds);
+    }
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct SphereOfInfluenceChanged {
+    pub entity: Entity,
+    pub previous_parent: Entity,
+    pub parent: Entity,
+}
+
+// The edge of a sphere of influence an entity can cross.
+enum Boundary<'a> {
+    // Leaving the parent's sphere of influence, for the grandparent's.
+    Exit {
+        grandparent: Entity,
+        grandparent_gravitational_parameter: Scalar,
+        parent_orbit: &'a Orbit,
+        radius: Scalar,
+    },
+    // Entering the sphere of influence of a body orbiting the same parent.
+    Entry {
+        sibling: Entity,
+        sibling_gravitational_parameter: Scalar,
+        sibling_orbit: &'a Orbit,
+        radius: Scalar,
+    },
+}
+
+impl Boundary<'_> {
+    // Distance left before crossing, negative once crossed, and how fast it can shrink, for a body
+    // at the given position and velocity relative to its parent.
+    fn margin(
+        &self,
+        position: Vector,
+        velocity: Vector,
+        gravitational_parameter: Scalar,
+        seconds: Scalar,
+    ) -> (Scalar, Scalar) {
+        match self {
+            Boundary::Exit { radius, .. } => ((1.0 + CROSSING_MARGIN) * radius - position.length(), velocity.length()),
+            Boundary::Entry {
+                sibling_orbit, radius, ..
+            } => {
+                let (sibling_position, sibling_velocity) = sibling_orbit.state_at(seconds, gravitational_parameter);
+                (
+                    (position - sibling_position).length() - (1.0 - CROSSING_MARGIN) * radius,
+                    (velocity - sibling_velocity).length(),
+                )
+            }
+        }
+    }
+
+    // The new parent, and the orbit around it starting from the given state.
+    fn transition(
+        &self,
+        position: Vector,
+        velocity: Vector,
+        gravitational_parameter: Scalar,
+        seconds: Scalar,
+    ) -> (Entity, Orbit) {
+        match *self {
+            Boundary::Exit {
+                grandparent,
+                grandparent_gravitational_parameter,
+                parent_orbit,
+                ..
+            } => {
+                let (parent_position, parent_velocity) =
+                    parent_orbit.state_at(seconds, grandparent_gravitational_parameter);
+                let orbit = Orbit::from_state_vectors(
+                    position + parent_position,
+                    velocity + parent_velocity,
+                    grandparent_gravitational_parameter,
+                    seconds,
+                );
+                (grandparent, orbit)
+            }
+            Boundary::Entry {
+                sibling,
+                sibling_gravitational_parameter,
+                sibling_orbit,
+                ..
+            } => {
+                let (sibling_position, sibling_velocity) = sibling_orbit.state_at(seconds, gravitational_parameter);
+                let orbit = Orbit::from_state_vectors(
+                    position - sibling_position,
+                    velocity - sibling_velocity,
+                    sibling_gravitational_parameter,
+                    seconds,
+                );
+                (sibling, orbit)
+            }
+        }
+    }
+}
+
+// The first time between `start` and `end` the orbit crosses one of the boundaries, with the index
+// of the boundary crossed. A boundary already crossed at `start` only counts if `check_start` is set,
+// so an orbit patched at a crossing isn't sent straight back by rounding errors.
+fn find_crossing(
+    orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    boundaries: &[Boundary],
+    start: Scalar,
+    end: Scalar,
+    check_start: bool,
+) -> Option<(Scalar, usize)> {
+    let margins = |seconds: Scalar| {
+        let (position, velocity) = orbit.state_at(seconds, gravitational_parameter);
+        boundaries
+            .iter()
+            .map(|boundary| boundary.margin(position, velocity, gravitational_parameter, seconds))
+            .collect::<Vec<_>>()
+    };
+    let crossed = |seconds: Scalar, index: usize| {
+        let (position, velocity) = orbit.state_at(seconds, gravitational_parameter);
+        boundaries[index].margin(position, velocity, gravitational_parameter, seconds).0 < 0.0
+    };
+
+    let mut previous_margins = margins(start);
+    if check_start {
+        if let Some(index) = previous_margins.iter().position(|&(margin, _)| margin < 0.0) {
+            return Some((start, index));
+        }
+    } else {
+        // a patched orbit starts on the boundary it crossed
+        for (margin, _) in &mut previous_margins {
+            *margin = margin.max(0.0);
+        }
+    }
+
+    // steps no longer than the time it takes to close the nearest gap at the current speed, so a
+    // sphere of influence isn't stepped over
+    let min_step = (end - start) / SEARCH_STEPS as Scalar;
+    let mut previous = start;
+    while previous < end {
+        let step = previous_margins
+            .iter()
+            .map(|&(margin, rate)| margin / rate)
+            .fold(Scalar::INFINITY, Scalar::min)
+            .max(min_step);
+        let seconds = (previous + step).min(end);
+        let current_margins = margins(seconds);
+
+        let crossing = previous_margins
+            .iter()
+            .zip(&current_margins)
+            .enumerate()
+            .filter(|(_, (&(before, _), &(after, _)))| before >= 0.0 && after < 0.0)
+            .map(|(index, _)| {
+                let (mut low, mut high) = (previous, seconds);
+                for _ in 0..BISECTION_ITERATIONS {
+                    let middle = 0.5 * (low + high);
+                    if middle <= low || middle >= high {
+                        break;
+                    }
+                    if crossed(middle, index) {
+                        high = middle;
+                    } else {
+                        low = middle;
+                    }
+                }
+                // the crossed side, so the new parent's checks agree the body is inside
+                (high, index)
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        if crossing.is_some() {
+            return crossing;
+        }
+
+        previous = seconds;
+        previous_margins = current_margins;
+    }
+
+    None
+}
+
+#[allow(clippy::type_complexity)]
+pub fn transition_spheres_of_influence(
+    mut commands: Commands,
+    time: Res<OrbitalTime>,
+    gravitational_constant: Res<GravitationalConstant>,
+    bodies: Query<(&Mass, Option<&Orbit>, Option<&Parent>, Option<&Children>), Without<PatchedConic>>,
+    mut orbits: Query<(
+        Entity,
+        &mut Orbit,
+        &Parent,
+        &mut PatchedConic,
+        Option<&mut PreserveTrueAnomaly>,
+        Has<TransferSchedule>,
+    )>,
+    mut sphere_of_influence_changed: EventWriter<SphereOfInfluenceChanged>,
+) {
+    let seconds = time.seconds();
+
+    for (entity, mut orbit, parent, mut patched_conic, mut maybe_preserve, has_schedule) in orbits.iter_mut() {
+        // `Parent` isn't updated until the commands run, so each transition below starts from the
+        // parent the previous one moved to
+        let mut current_parent = parent.get();
+
+        // entities with a schedule have their transitions undone along with their maneuvers
+        if !has_schedule {
+            while let Some(reverted) = patched_conic.reverted_transition(seconds, None) {
+                *orbit = reverted.previous_orbit;
+                if let Some(preserve) = maybe_preserve.as_mut() {
+                    preserve.ignore_change();
+                }
+                commands.entity(entity).set_parent(reverted.previous_parent);
+                current_parent = reverted.previous_parent;
+            }
+        }
+
+        // only the path travelled forwards since the last check is searched, and a new entity is
+        // checked where it is
+        let mut check_start = patched_conic.checked_until.is_none();
+        let mut start = patched_conic.checked_until.map_or(seconds, |checked| checked.min(seconds));
+        patched_conic.checked_until = Some(seconds);
+
+        for _ in 0..MAX_TRANSITIONS {
+            let Ok((parent_mass, maybe_parent_orbit, maybe_grandparent, maybe_siblings)) = bodies.get(current_parent)
+            else {
+                break;
+            };
+            let gravitational_parameter = parent_mass.gravitational_parameter(&gravitational_constant);
+
+            let mut boundaries = Vec::new();
+            if let (Some(parent_orbit), Some(grandparent)) = (maybe_parent_orbit, maybe_grandparent) {
+                if let Ok((grandparent_mass, ..)) = bodies.get(grandparent.get()) {
+                    boundaries.push(Boundary::Exit {
+                        grandparent: grandparent.get(),
+                        grandparent_gravitational_parameter: grandparent_mass
+                            .gravitational_parameter(&gravitational_constant),
+                        parent_orbit,
+                        radius: parent_mass.sphere_of_influence(
+                            parent_orbit,
+                            grandparent_mass,
+                            &gravitational_constant,
+                        ),
+                    });
+                }
+            }
+            for &sibling in maybe_siblings.into_iter().flatten() {
+                let Ok((sibling_mass, Some(sibling_orbit), ..)) = bodies.get(sibling) else {
+                    continue;
+                };
+                boundaries.push(Boundary::Entry {
+                    sibling,
+                    sibling_gravitational_parameter: sibling_mass.gravitational_parameter(&gravitational_constant),
+                    sibling_orbit,
+                    radius: sibling_mass.sphere_of_influence(sibling_orbit, parent_mass, &gravitational_constant),
+                });
+            }
+
+            let Some((crossing_time, index)) = find_crossing(
+                &orbit,
+                gravitational_parameter,
+                &boundaries,
+                start,
+                seconds,
+                check_start,
+            ) else {
+                break;
+            };
+
+            let (position, velocity) = orbit.state_at(crossing_time, gravitational_parameter);
+            let (new_parent, new_orbit) =
+                boundaries[index].transition(position, velocity, gravitational_parameter, crossing_time);
+
+            patched_conic.history.push(SphereOfInfluenceTransition {
+                time: crossing_time,
+                previous_orbit: std::mem::replace(&mut *orbit, new_orbit),
+                previous_parent: current_parent,
+                parent: new_parent,
+            });
+            if let Some(preserve) = maybe_preserve.as_mut() {
+                preserve.ignore_change();
+            }
+            commands.entity(entity).set_parent(new_parent);
+            sphere_of_influence_changed.send(SphereOfInfluenceChanged {
+                entity,
+                previous_parent: current_parent,
+                parent: new_parent,
+            });
+
+            current_parent = new_parent;
+            start = crossing_time;
+            check_start = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::OrbitPlugin;
+
+    const SUN_GRAVITATIONAL_PARAMETER: Scalar = 1000.0;
+    const PLANET_GRAVITATIONAL_PARAMETER: Scalar = 10.0;
+
+    // Relative to the size of the position and velocity.
+    const TOLERANCE: Scalar = 1e3 * Scalar::EPSILON;
+
+    struct System {
+        app: App,
+        sun: Entity,
+        planet: Entity,
+        planet_orbit: Orbit,
+    }
+
+    // A planet with a sphere of influence about 16 across, on a circular orbit around the sun.
+    fn system() -> System {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin, OrbitPlugin::default()));
+        app.world_mut().resource_mut::<OrbitalTime>().paused = true;
+
+        let planet_orbit = Orbit {
+            semi_major_axis: 100.0,
+            eccentricity: 0.0,
+            argument_of_periapsis: 0.0,
+            inclination: 0.0,
+            longitude_of_ascending_node: 0.0,
+            initial_mean_anomaly: 0.0,
+        };
+        let sun = app
+            .world_mut()
+            .spawn((
+                Mass::GravitationalParameter(SUN_GRAVITATIONAL_PARAMETER),
+                TransformBundle::default(),
+            ))
+            .id();
+        let planet = app
+            .world_mut()
+            .spawn((
+                planet_orbit.clone(),
+                Mass::GravitationalParameter(PLANET_GRAVITATIONAL_PARAMETER),
+                TransformBundle::default(),
+            ))
+            .set_parent(sun)
+            .id();
+
+        System {
+            app,
+            sun,
+            planet,
+            planet_orbit,
+        }
+    }
+
+    fn spawn_patched_conic(system: &mut System, orbit: Orbit, parent: Entity) -> Entity {
+        let entity = system
+            .app
+            .world_mut()
+            .spawn((orbit, PatchedConic::default(), TransformBundle::default()))
+            .set_parent(parent)
+            .id();
+        system.app.update();
+        entity
+    }
+
+    fn jump_to(system: &mut System, seconds: Scalar) {
+        system.app.world_mut().resource_mut::<OrbitalTime>().set_seconds(seconds);
+        system.app.update();
+    }
+
+    fn parent_of(system: &System, entity: Entity) -> Entity {
+        system.app.world().get::<Parent>(entity).unwrap().get()
+    }
+
+    fn history(system: &System, entity: Entity) -> Vec<SphereOfInfluenceTransition> {
+        system.app.world().get::<PatchedConic>(entity).unwrap().history.clone()
+    }
+
+    // Position and velocity relative to the sun.
+    fn state_around_sun(system: &System, orbit: &Orbit, parent: Entity, seconds: Scalar) -> (Vector, Vector) {
+        if parent == system.sun {
+            return orbit.state_at(seconds, SUN_GRAVITATIONAL_PARAMETER);
+        }
+
+        let (position, velocity) = orbit.state_at(seconds, PLANET_GRAVITATIONAL_PARAMETER);
+        let (planet_position, planet_velocity) = system.planet_orbit.state_at(seconds, SUN_GRAVITATIONAL_PARAMETER);
+        (position + planet_position, velocity + planet_velocity)
+    }
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!((a - b).length() <= TOLERANCE * a.length().max(b.length()), "{a} != {b}");
+    }
+
+    // Escaping the planet, faster than its escape velocity.
+    fn escape_orbit() -> Orbit {
+        Orbit::from_state_vectors(
+            Vector::new(5.0, 0.0, 0.0),
+            Vector::new(0.0, 0.0, -3.0),
+            PLANET_GRAVITATIONAL_PARAMETER,
+            0.0,
+        )
+    }
+
+    #[test]
+    fn escapes_to_the_grandparent_at_the_crossing() {
+        let mut system = system();
+        let planet = system.planet;
+        let entity = spawn_patched_conic(&mut system, escape_orbit(), planet);
+        system.app.world_mut().resource_mut::<Events<SphereOfInfluenceChanged>>().clear();
+
+        jump_to(&mut system, 100.0);
+        assert_eq!(parent_of(&system, entity), system.sun);
+
+        let events: Vec<_> =
+            system.app.world_mut().resource_mut::<Events<SphereOfInfluenceChanged>>().drain().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].entity, entity);
+        assert_eq!(events[0].previous_parent, system.planet);
+        assert_eq!(events[0].parent, system.sun);
+
+        let history = history(&system, entity);
+        assert_eq!(history.len(), 1);
+        let transition = &history[0];
+        assert_eq!(transition.previous_orbit, escape_orbit());
+        assert_eq!(transition.previous_parent, system.planet);
+        assert_eq!(transition.parent, system.sun);
+
+        // on the edge of the sphere of influence, with no jump in position or velocity
+        let (position, _) = escape_orbit().state_at(transition.time, PLANET_GRAVITATIONAL_PARAMETER);
+        let sphere_of_influence = Mass::GravitationalParameter(PLANET_GRAVITATIONAL_PARAMETER).sphere_of_influence(
+            &system.planet_orbit,
+            &Mass::GravitationalParameter(SUN_GRAVITATIONAL_PARAMETER),
+            &GravitationalConstant::default(),
+        );
+        assert!((position.length() - sphere_of_influence).abs() <= TOLERANCE.sqrt() * sphere_of_influence);
+
+        let orbit = system.app.world().get::<Orbit>(entity).unwrap().clone();
+        let (before_position, before_velocity) =
+            state_around_sun(&system, &escape_orbit(), system.planet, transition.time);
+        let (after_position, after_velocity) = state_around_sun(&system, &orbit, system.sun, transition.time);
+        assert_close(before_position, after_position);
+        assert_close(before_velocity, after_velocity);
+    }
+
+    #[test]
+    fn rewinding_restores_the_previous_orbit_and_parent() {
+        let mut system = system();
+        let planet = system.planet;
+        let entity = spawn_patched_conic(&mut system, escape_orbit(), planet);
+
+        jump_to(&mut system, 100.0);
+        let crossing_time = history(&system, entity)[0].time;
+
+        jump_to(&mut system, crossing_time - 1.0);
+        assert_eq!(parent_of(&system, entity), system.planet);
+        assert_eq!(*system.app.world().get::<Orbit>(entity).unwrap(), escape_orbit());
+        assert!(history(&system, entity).is_empty());
+
+        // and crosses at the same time going forwards again
+        jump_to(&mut system, 100.0);
+        assert_eq!(parent_of(&system, entity), system.sun);
+        assert_eq!(history(&system, entity)[0].time, crossing_time);
+    }
+
+    #[test]
+    fn a_flyby_in_one_frame_matches_small_steps() {
+        const END: Scalar = 60.0;
+
+        // passes through the planet's sphere of influence between about 7 and 20 seconds
+        let speed = (SUN_GRAVITATIONAL_PARAMETER / 100.0).sqrt() + 2.0;
+        let orbit = Orbit::from_state_vectors(
+            Vector::new(103.0, 0.0, 30.0),
+            Vector::new(0.0, 0.0, -speed),
+            SUN_GRAVITATIONAL_PARAMETER,
+            0.0,
+        );
+
+        let mut jumped = system();
+        let sun = jumped.sun;
+        let jumped_entity = spawn_patched_conic(&mut jumped, orbit.clone(), sun);
+        jump_to(&mut jumped, END);
+
+        let mut stepped = system();
+        let sun = stepped.sun;
+        let stepped_entity = spawn_patched_conic(&mut stepped, orbit, sun);
+        for step in 1..=600 {
+            jump_to(&mut stepped, END * step as Scalar / 600.0);
+        }
+
+        let jumped_history = history(&jumped, jumped_entity);
+        let stepped_history = history(&stepped, stepped_entity);
+        assert_eq!(jumped_history.len(), 2);
+        assert_eq!(jumped_history[0].parent, jumped.planet);
+        assert_eq!(jumped_history[1].parent, jumped.sun);
+        assert_eq!(stepped_history.len(), 2);
+        for (a, b) in jumped_history.iter().zip(&stepped_history) {
+            assert!((a.time - b.time).abs() <= TOLERANCE * END, "{} != {}", a.time, b.time);
+        }
+
+        let jumped_orbit = jumped.app.world().get::<Orbit>(jumped_entity).unwrap();
+        let stepped_orbit = stepped.app.world().get::<Orbit>(stepped_entity).unwrap();
+        let (jumped_position, _) = jumped_orbit.state_at(END, SUN_GRAVITATIONAL_PARAMETER);
+        let (stepped_position, _) = stepped_orbit.state_at(END, SUN_GRAVITATIONAL_PARAMETER);
+        assert!((jumped_position - stepped_position).length() <= TOLERANCE.sqrt() * jumped_position.length());
+    }
+}
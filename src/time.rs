@@ -0,0 +1,90 @@
+use bevy::prelude::*;
+
+use crate::math::{as_f64, Scalar};
+
+// The clock all orbits are calculated from. It advances with `Time`, but can be warped, paused
+// and jumped to any time without affecting the rest of the game.
+#[derive(Resource, Clone, Debug)]
+pub struct OrbitalTime {
+    // Simulation time the clock starts at.
+    pub epoch: Scalar,
+    pub time_scale: Scalar,
+    pub paused: bool,
+    // Kept in double precision whatever `Scalar` is, so adding short frames to a long elapsed
+    // time doesn't lose them.
+    elapsed: f64,
+}
+
+impl Default for OrbitalTime {
+    fn default() -> Self {
+        OrbitalTime::from_epoch(0.0)
+    }
+}
+
+impl OrbitalTime {
+    pub fn from_epoch(epoch: Scalar) -> OrbitalTime {
+        OrbitalTime {
+            epoch,
+            time_scale: 1.0,
+            paused: false,
+            elapsed: 0.0,
+        }
+    }
+
+    // Current simulation time, in seconds.
+    pub fn seconds(&self) -> Scalar {
+        (as_f64(self.epoch) + self.elapsed) as Scalar
+    }
+
+    // Jumps to the given simulation time, which may be in the past.
+    pub fn set_seconds(&mut self, seconds: Scalar) {
+        self.elapsed = as_f64(seconds) - as_f64(self.epoch);
+    }
+
+    // Seconds of simulation time since the epoch.
+    pub fn elapsed_seconds(&self) -> Scalar {
+        self.elapsed as Scalar
+    }
+
+    // Advances simulation time, ignoring the time scale and pause.
+    pub fn advance_by(&mut self, seconds: Scalar) {
+        self.elapsed += as_f64(seconds);
+    }
+}
+
+pub fn update_orbital_time(time: Res<Time>, mut orbital_time: ResMut<OrbitalTime>) {
+    if orbital_time.paused {
+        return;
+    }
+
+    let delta = time.delta().as_secs_f64() * as_f64(orbital_time.time_scale);
+    orbital_time.elapsed += delta;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advances_at_the_right_rate_after_a_long_time() {
+        const FRAME: Scalar = 1.0 / 60.0;
+        const FRAMES: u32 = 60 * 60 * 60;
+
+        for start in [0.0, 86_400.0, 1e6] {
+            let mut time = OrbitalTime::default();
+            time.set_seconds(start);
+            for _ in 0..FRAMES {
+                time.advance_by(FRAME);
+            }
+
+            let expected = as_f64(start) + as_f64(FRAME) * f64::from(FRAMES);
+            // rounding to `Scalar` once, and to f64 every frame
+            let tolerance = expected * (as_f64(Scalar::EPSILON) + f64::from(FRAMES) * f64::EPSILON);
+            assert!(
+                (as_f64(time.seconds()) - expected).abs() <= tolerance,
+                "{start}: {} != {expected}",
+                time.seconds()
+            );
+        }
+    }
+}
@@ -1,16 +1,67 @@
 use std::collections::VecDeque;
-use std::f32::consts::{PI, TAU};
+use std::fmt;
 
 use bevy::prelude::*;
 
 use crate::math::*;
-use crate::orbit::Orbit;
+use crate::orbit::{GravitationalConstant, Mass, Orbit, PreserveTrueAnomaly};
+use crate::soi::PatchedConic;
+use crate::time::OrbitalTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    // Transfers can only be calculated between elliptic orbits.
+    OpenOrbit,
+    // No transfer of the requested kind reaches the target orbit.
+    NoSolution,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::OpenOrbit => write!(f, "transfers can only be calculated between elliptic orbits"),
+            TransferError::NoSolution => write!(f, "no transfer reaches the target orbit"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
 
 #[derive(Debug, Clone)]
 pub struct Maneuver {
     pub start_orbit: Orbit,
+    // Relative to the new parent, if the maneuver changes parent.
     pub target_orbit: Orbit,
-    pub execution_time: f32,
+    pub execution_time: Scalar,
+    pub parent_change: Option<ParentChange>,
+}
+
+// Moves the body to a new parent when a maneuver is executed, as it crosses into another sphere
+// of influence.
+#[derive(Debug, Clone, Copy)]
+pub struct ParentChange {
+    pub parent: Entity,
+    pub gravitational_parameter: Scalar,
+}
+
+impl Maneuver {
+    // The burn needed at execution time, as prograde, normal and radial (outwards) components
+    // relative to the start orbit's velocity. Changing parent takes no burn.
+    pub fn delta_v(&self, gravitational_parameter: Scalar) -> Vector {
+        if self.parent_change.is_some() {
+            return Vector::ZERO;
+        }
+
+        let (position, start_velocity) = self.start_orbit.state_at(self.execution_time, gravitational_parameter);
+        let (_, target_velocity) = self.target_orbit.state_at(self.execution_time, gravitational_parameter);
+        let burn = target_velocity - start_velocity;
+
+        let prograde = start_velocity.normalize_or_zero();
+        let normal = position.cross(start_velocity).normalize_or_zero();
+        let radial = prograde.cross(normal);
+
+        Vector::new(burn.dot(prograde), burn.dot(normal), burn.dot(radial))
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -18,9 +69,67 @@ pub struct Transfer {
     pub maneuvers: VecDeque<Maneuver>,
 }
 
+impl Transfer {
+    // Takes the gravitational parameter of the parent at the start of the transfer, following any parent changes.
+    pub fn total_delta_v(&self, gravitational_parameter: Scalar) -> Scalar {
+        let mut gravitational_parameter = gravitational_parameter;
+        self.maneuvers
+            .iter()
+            .map(|maneuver| {
+                let delta_v = maneuver.delta_v(gravitational_parameter).length();
+                if let Some(parent_change) = maneuver.parent_change {
+                    gravitational_parameter = parent_change.gravitational_parameter;
+                }
+                delta_v
+            })
+            .sum()
+    }
+
+    // Seconds from the first maneuver to the last.
+    pub fn duration(&self) -> Scalar {
+        match (self.maneuvers.front(), self.maneuvers.back()) {
+            (Some(first), Some(last)) => last.execution_time - first.execution_time,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutedManeuver {
+    pub maneuver: Maneuver,
+    // Whether this was the first maneuver of its transfer.
+    pub started_transfer: bool,
+    // Whether this was the last maneuver of its transfer.
+    pub completed_transfer: bool,
+    // Seconds between the maneuver's execution time and when it was actually applied.
+    pub lateness: Scalar,
+    // The parent before the maneuver, restored if a parent change is undone.
+    pub previous_parent: Option<Entity>,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct ManeuverExecuted {
+    pub entity: Entity,
+    pub maneuver: Maneuver,
+    pub delta_v: Vector,
+    pub lateness: Scalar,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct TransferStarted {
+    pub entity: Entity,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct TransferCompleted {
+    pub entity: Entity,
+}
+
 #[derive(Component, Default, Clone, Debug)]
 pub struct TransferSchedule {
     pub transfers: VecDeque<Transfer>,
+    // Executed maneuvers, oldest first, kept so they can be undone if time goes backwards.
+    pub history: Vec<ExecutedManeuver>,
 }
 
 impl TransferSchedule {
@@ -28,7 +137,26 @@ impl TransferSchedule {
         self.transfers.push_back(transfer);
     }
 
-    fn overdue_maneuver(&mut self, seconds: f32) -> Option<Maneuver> {
+    // The orbit the body should be on at the given time, or `None` if the schedule has no
+    // maneuvers to say.
+    pub fn orbit_at(&self, seconds: Scalar) -> Option<&Orbit> {
+        let executed = self.history.iter().map(|executed| &executed.maneuver);
+        let pending = self.transfers.iter().flat_map(|transfer| transfer.maneuvers.iter());
+        let mut maneuvers = executed.chain(pending).peekable();
+
+        let first_maneuver = maneuvers.peek()?;
+        if seconds < first_maneuver.execution_time {
+            return Some(&first_maneuver.start_orbit);
+        }
+
+        maneuvers
+            .take_while(|maneuver| maneuver.execution_time <= seconds)
+            .last()
+            .map(|maneuver| &maneuver.target_orbit)
+    }
+
+    fn overdue_maneuver(&mut self, seconds: Scalar, parent: Option<Entity>) -> Option<ExecutedManeuver> {
+        let started_transfer = self.history.last().map_or(true, |executed| executed.completed_transfer);
         let next_transfer = self.transfers.front_mut()?;
         let maybe_next_maneuver = next_transfer.maneuvers.front()?;
 
@@ -36,85 +164,644 @@ impl TransferSchedule {
             return None;
         };
 
-        let next_maneuver = next_transfer.maneuvers.pop_front();
+        let next_maneuver = next_transfer.maneuvers.pop_front()?;
+        let completed_transfer = next_transfer.maneuvers.is_empty();
 
-        if next_transfer.maneuvers.is_empty() {
+        if completed_transfer {
             self.transfers.pop_front();
         }
 
-        next_maneuver
+        let executed = ExecutedManeuver {
+            lateness: seconds - next_maneuver.execution_time,
+            maneuver: next_maneuver,
+            started_transfer,
+            completed_transfer,
+            previous_parent: parent,
+        };
+        self.history.push(executed.clone());
+
+        Some(executed)
+    }
+
+    fn reverted_maneuver(&mut self, seconds: Scalar) -> Option<ExecutedManeuver> {
+        let last_executed = self.history.last()?;
+
+        if seconds >= last_executed.maneuver.execution_time {
+            return None;
+        }
+
+        let executed = self.history.pop()?;
+        let maneuver = &executed.maneuver;
+
+        match self.transfers.front_mut() {
+            Some(remaining_transfer) if !executed.completed_transfer => {
+                remaining_transfer.maneuvers.push_front(maneuver.clone());
+            }
+            _ => {
+                self.transfers.push_front(Transfer {
+                    maneuvers: VecDeque::from([maneuver.clone()]),
+                });
+            }
+        }
+
+        Some(executed)
     }
 }
 
-pub fn execute_orbital_maneuvers(time: Res<Time>, mut query: Query<(&mut Orbit, &mut TransferSchedule)>) {
-    let seconds = time.elapsed_seconds();
-    for (mut orbit, mut schedule) in query.iter_mut() {
-        if let Some(next_maneuver) = schedule.overdue_maneuver(seconds) {
-            orbit.semi_major_axis = next_maneuver.target_orbit.semi_major_axis;
-            orbit.eccentricity = next_maneuver.target_orbit.eccentricity;
-            orbit.argument_of_periapsis = next_maneuver.target_orbit.argument_of_periapsis;
-            orbit.initial_mean_anomaly = next_maneuver.target_orbit.initial_mean_anomaly;
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn execute_orbital_maneuvers(
+    mut commands: Commands,
+    time: Res<OrbitalTime>,
+    gravitational_constant: Res<GravitationalConstant>,
+    masses: Query<&Mass>,
+    mut query: Query<(
+        Entity,
+        &mut Orbit,
+        &mut TransferSchedule,
+        Option<&Parent>,
+        Option<&mut PreserveTrueAnomaly>,
+        Option<&mut PatchedConic>,
+    )>,
+    mut maneuver_executed: EventWriter<ManeuverExecuted>,
+    mut transfer_started: EventWriter<TransferStarted>,
+    mut transfer_completed: EventWriter<TransferCompleted>,
+) {
+    let seconds = time.seconds();
+    for (entity, mut orbit, mut schedule, maybe_parent, mut maybe_preserve, mut maybe_patched_conic) in query.iter_mut()
+    {
+        // parent changes are deferred, so the current parent is tracked here
+        let mut maybe_current_parent = maybe_parent.map(|parent| parent.get());
+
+        // sphere of influence transitions and maneuvers are undone together, latest first
+        loop {
+            let last_maneuver_time = schedule.history.last().map(|executed| executed.maneuver.execution_time);
+            if let Some(reverted) = maybe_patched_conic
+                .as_mut()
+                .and_then(|patched_conic| patched_conic.reverted_transition(seconds, last_maneuver_time))
+            {
+                *orbit = reverted.previous_orbit;
+                if let Some(preserve) = maybe_preserve.as_mut() {
+                    preserve.ignore_change();
+                }
+                set_parent(&mut commands, entity, Some(reverted.previous_parent));
+                maybe_current_parent = Some(reverted.previous_parent);
+                continue;
+            }
+
+            let Some(reverted) = schedule.reverted_maneuver(seconds) else {
+                break;
+            };
+            *orbit = reverted.maneuver.start_orbit;
+            if let Some(preserve) = maybe_preserve.as_mut() {
+                preserve.ignore_change();
+            }
+            if reverted.maneuver.parent_change.is_some() {
+                set_parent(&mut commands, entity, reverted.previous_parent);
+                maybe_current_parent = reverted.previous_parent;
+            }
+        }
+
+        // under time warp or after a long frame several maneuvers can be overdue at once
+        while let Some(executed) = schedule.overdue_maneuver(seconds, maybe_current_parent) {
+            *orbit = executed.maneuver.target_orbit.clone();
+            if let Some(preserve) = maybe_preserve.as_mut() {
+                preserve.ignore_change();
+            }
+            if let Some(patched_conic) = maybe_patched_conic.as_mut() {
+                patched_conic.restart_at(executed.maneuver.execution_time);
+            }
+
+            if executed.started_transfer {
+                transfer_started.send(TransferStarted { entity });
+            }
+
+            let maybe_parent_mass = maybe_current_parent.and_then(|parent| masses.get(parent).ok());
+            let delta_v = maybe_parent_mass.map_or(Vector::ZERO, |parent_mass| {
+                executed.maneuver.delta_v(parent_mass.gravitational_parameter(&gravitational_constant))
+            });
+
+            if let Some(parent_change) = executed.maneuver.parent_change {
+                set_parent(&mut commands, entity, Some(parent_change.parent));
+                maybe_current_parent = Some(parent_change.parent);
+            }
+
+            maneuver_executed.send(ManeuverExecuted {
+                entity,
+                maneuver: executed.maneuver,
+                delta_v,
+                lateness: executed.lateness,
+            });
+
+            if executed.completed_transfer {
+                transfer_completed.send(TransferCompleted { entity });
+            }
         }
     }
 }
 
+fn set_parent(commands: &mut Commands, entity: Entity, maybe_parent: Option<Entity>) {
+    match maybe_parent {
+        Some(parent) => commands.entity(entity).set_parent(parent),
+        None => commands.entity(entity).remove_parent(),
+    };
+}
+
+// Two burn transfer leaving the start orbit at the first suitable point after `execution_time`.
+// Between coplanar orbits the transfer departs from an apsis of the start orbit (or arrives at
+// an apsis of the target orbit, if the start is circular), otherwise it departs from where the
+// two orbital planes intersect and the arrival burn also changes plane.
 pub fn calculate_hohmann_transfer(
     start_orbit: &Orbit,
     target_orbit: &Orbit,
-    parent_mass: f32,
-    execution_time: f32,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if start_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let departure_directions =
+        calculate_departure_directions(start_orbit, target_orbit, gravitational_parameter, execution_time);
+    let transfers = departure_directions.into_iter().map(|departure_direction| {
+        calculate_half_orbit_transfer(
+            start_orbit,
+            target_orbit,
+            departure_direction,
+            false,
+            gravitational_parameter,
+            execution_time,
+        )
+    });
+    cheapest_transfer(transfers, gravitational_parameter)
+}
+
+// Where transfers between the orbits depart from: where the orbital planes intersect, or between
+// coplanar orbits an apsis of the start orbit (or of the target orbit, if the start is circular).
+fn calculate_departure_directions(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Vec<Vector> {
+    let (start_periapsis, _, start_normal) = start_orbit.perifocal_axes();
+    let (target_periapsis, _, target_normal) = target_orbit.perifocal_axes();
+    let line_of_nodes = start_normal.cross(target_normal);
+
+    if line_of_nodes.length() > ORBIT_TOLERANCE {
+        vec![line_of_nodes.normalize(), -line_of_nodes.normalize()]
+    } else if start_orbit.eccentricity > ORBIT_TOLERANCE {
+        vec![start_periapsis, -start_periapsis]
+    } else if target_orbit.eccentricity > ORBIT_TOLERANCE {
+        vec![-target_periapsis, target_periapsis]
+    } else {
+        let (start_position, _) = start_orbit.state_at(execution_time, gravitational_parameter);
+        vec![start_position.normalize()]
+    }
+}
+
+// Transfer along half of an ellipse, from the start orbit where it crosses the departure
+// direction to the target orbit on the opposite side of the parent. Any change of plane is made
+// by the arrival burn, unless `plane_change_at_apoapsis` is set and the departure is higher.
+pub(crate) fn calculate_half_orbit_transfer(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    departure_direction: Vector,
+    plane_change_at_apoapsis: bool,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
 ) -> Transfer {
-    let start_period = calculate_period(start_orbit.semi_major_axis, parent_mass);
-    let start_mean_motion = calculate_mean_motion(start_period);
-    let start_mean_anomaly = calculate_mean_anomaly(
-        start_mean_motion,
-        start_orbit.initial_mean_anomaly + start_orbit.argument_of_periapsis,
-        execution_time,
-    );
+    let departure_true_anomaly = start_orbit.true_anomaly_of_direction(departure_direction);
+    let departure_time =
+        start_orbit.time_of_true_anomaly(departure_true_anomaly, execution_time, gravitational_parameter);
+    let (departure_position, departure_velocity) = start_orbit.state_at(departure_time, gravitational_parameter);
+    let departure_radius = departure_position.length();
 
-    let transfer_semi_major_axis = (start_orbit.semi_major_axis + target_orbit.semi_major_axis) / 2.0;
-    let transfer_eccentricity = (1.0 - start_orbit.semi_major_axis / transfer_semi_major_axis).abs();
-    let transfer_period = calculate_period(transfer_semi_major_axis, parent_mass);
-    let transfer_argument_of_periapsis_offset = if start_orbit.semi_major_axis < transfer_semi_major_axis {
-        0.0
+    let arrival_true_anomaly = target_orbit.true_anomaly_of_direction(-departure_direction);
+    let arrival_radius = calculate_semilatus_rectum(target_orbit.semi_major_axis, target_orbit.eccentricity)
+        / (1.0 + target_orbit.eccentricity * arrival_true_anomaly.cos());
+
+    let transfer_semi_major_axis = (departure_radius + arrival_radius) / 2.0;
+    let transfer_speed = calculate_vis_viva_speed(departure_radius, transfer_semi_major_axis, gravitational_parameter);
+    let transfer_normal = if plane_change_at_apoapsis && departure_radius > arrival_radius {
+        target_orbit.perifocal_axes().2
     } else {
-        PI
-    };
-    let transfer_argument_of_periapsis = -(start_mean_anomaly + transfer_argument_of_periapsis_offset).rem_euclid(TAU);
-    let transfer_initial_mean_anomaly =
-        calculate_initial_mean_anomaly(transfer_argument_of_periapsis_offset, transfer_period, execution_time);
-    let transfer_orbit = Orbit {
-        semi_major_axis: transfer_semi_major_axis,
-        eccentricity: transfer_eccentricity,
-        argument_of_periapsis: transfer_argument_of_periapsis,
-        initial_mean_anomaly: transfer_initial_mean_anomaly,
+        departure_position.cross(departure_velocity)
     };
+    let horizontal = transfer_normal.cross(departure_position).normalize();
+    let transfer_orbit = Orbit::from_state_vectors(
+        departure_position,
+        horizontal * transfer_speed,
+        gravitational_parameter,
+        departure_time,
+    );
+    let arrival_time = departure_time + calculate_period(transfer_semi_major_axis, gravitational_parameter) / 2.0;
 
-    let enter_transfer_orbit_time = execution_time;
-    let exit_transfer_orbit_time = execution_time + transfer_period / 2.0;
-
-    let target_period = calculate_period(target_orbit.semi_major_axis, parent_mass);
-    let target_initial_mean_anomaly =
-        calculate_initial_mean_anomaly(PI + start_mean_anomaly, target_period, exit_transfer_orbit_time);
-    let actual_target_orbit = Orbit {
-        semi_major_axis: target_orbit.semi_major_axis,
-        eccentricity: target_orbit.eccentricity,
-        argument_of_periapsis: 0.0,
-        initial_mean_anomaly: target_initial_mean_anomaly,
-    };
+    let arrival_orbit = target_orbit.with_true_anomaly_at(arrival_true_anomaly, arrival_time, gravitational_parameter);
 
     let maneuver_1 = Maneuver {
         start_orbit: start_orbit.clone(),
         target_orbit: transfer_orbit.clone(),
-        execution_time: enter_transfer_orbit_time,
+        execution_time: departure_time,
+        parent_change: None,
     };
     let maneuver_2 = Maneuver {
         start_orbit: transfer_orbit,
-        target_orbit: actual_target_orbit,
-        execution_time: exit_transfer_orbit_time,
+        target_orbit: arrival_orbit,
+        execution_time: arrival_time,
+        parent_change: None,
     };
 
     Transfer {
         maneuvers: vec![maneuver_1, maneuver_2].into(),
     }
 }
+
+// Two burn transfer that leaves the start orbit at `departure_time` and meets the body on the
+// target orbit at `arrival_time`, travelling the same way around the parent as the start orbit.
+pub fn calculate_lambert_transfer(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    departure_time: Scalar,
+    arrival_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    let (departure_position, start_velocity) = start_orbit.state_at(departure_time, gravitational_parameter);
+    let (arrival_position, _) = target_orbit.state_at(arrival_time, gravitational_parameter);
+
+    let (departure_velocity, _) = calculate_lambert_velocities(
+        departure_position,
+        arrival_position,
+        arrival_time - departure_time,
+        departure_position.cross(start_velocity),
+        gravitational_parameter,
+    )
+    .ok_or(TransferError::NoSolution)?;
+    let transfer_orbit = Orbit::from_state_vectors(
+        departure_position,
+        departure_velocity,
+        gravitational_parameter,
+        departure_time,
+    );
+
+    let maneuver_1 = Maneuver {
+        start_orbit: start_orbit.clone(),
+        target_orbit: transfer_orbit.clone(),
+        execution_time: departure_time,
+        parent_change: None,
+    };
+    let maneuver_2 = Maneuver {
+        start_orbit: transfer_orbit,
+        target_orbit: target_orbit.clone(),
+        execution_time: arrival_time,
+        parent_change: None,
+    };
+
+    Ok(Transfer {
+        maneuvers: vec![maneuver_1, maneuver_2].into(),
+    })
+}
+
+// Three burn transfer via an intermediate apoapsis beyond both orbits, which needs less delta-v
+// than a Hohmann transfer when the target is much larger than the start. It departs from the same
+// points as a Hohmann transfer and arrives back on the same side of the parent, with any change
+// of plane made at the intermediate apoapsis, where it costs the least.
+pub fn calculate_bi_elliptic_transfer(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    intermediate_apoapsis: Scalar,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    if start_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let transfers = calculate_departure_directions(start_orbit, target_orbit, gravitational_parameter, execution_time)
+        .into_iter()
+        .filter_map(|departure_direction| {
+            calculate_bi_elliptic_transfer_from(
+                start_orbit,
+                target_orbit,
+                departure_direction,
+                intermediate_apoapsis,
+                gravitational_parameter,
+                execution_time,
+            )
+        });
+    cheapest_transfer(transfers, gravitational_parameter)
+}
+
+// Bi-elliptic transfer leaving the start orbit where it crosses the departure direction, or
+// `None` if the intermediate apoapsis is below the departure or arrival.
+fn calculate_bi_elliptic_transfer_from(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    departure_direction: Vector,
+    intermediate_apoapsis: Scalar,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Option<Transfer> {
+    let departure_true_anomaly = start_orbit.true_anomaly_of_direction(departure_direction);
+    let departure_time =
+        start_orbit.time_of_true_anomaly(departure_true_anomaly, execution_time, gravitational_parameter);
+    let (departure_position, departure_velocity) = start_orbit.state_at(departure_time, gravitational_parameter);
+    let departure_radius = departure_position.length();
+
+    let arrival_true_anomaly = target_orbit.true_anomaly_of_direction(departure_direction);
+    let arrival_radius = calculate_semilatus_rectum(target_orbit.semi_major_axis, target_orbit.eccentricity)
+        / (1.0 + target_orbit.eccentricity * arrival_true_anomaly.cos());
+
+    if intermediate_apoapsis < departure_radius || intermediate_apoapsis < arrival_radius {
+        return None;
+    }
+
+    let first_semi_major_axis = (departure_radius + intermediate_apoapsis) / 2.0;
+    let first_speed = calculate_vis_viva_speed(departure_radius, first_semi_major_axis, gravitational_parameter);
+    let first_horizontal = departure_position.cross(departure_velocity).cross(departure_position).normalize();
+    let first_orbit = Orbit::from_state_vectors(
+        departure_position,
+        first_horizontal * first_speed,
+        gravitational_parameter,
+        departure_time,
+    );
+    let second_execution_time = departure_time + calculate_period(first_semi_major_axis, gravitational_parameter) / 2.0;
+
+    // the intermediate apoapsis is on the line of nodes, so the plane can be changed there
+    let apoapsis_position = -departure_position.normalize() * intermediate_apoapsis;
+    let second_semi_major_axis = (intermediate_apoapsis + arrival_radius) / 2.0;
+    let second_speed = calculate_vis_viva_speed(intermediate_apoapsis, second_semi_major_axis, gravitational_parameter);
+    let (_, _, target_normal) = target_orbit.perifocal_axes();
+    let second_horizontal = target_normal.cross(apoapsis_position).normalize();
+    let second_orbit = Orbit::from_state_vectors(
+        apoapsis_position,
+        second_horizontal * second_speed,
+        gravitational_parameter,
+        second_execution_time,
+    );
+    let third_execution_time =
+        second_execution_time + calculate_period(second_semi_major_axis, gravitational_parameter) / 2.0;
+
+    let third_orbit =
+        target_orbit.with_true_anomaly_at(arrival_true_anomaly, third_execution_time, gravitational_parameter);
+
+    let maneuver_1 = Maneuver {
+        start_orbit: start_orbit.clone(),
+        target_orbit: first_orbit.clone(),
+        execution_time: departure_time,
+        parent_change: None,
+    };
+    let maneuver_2 = Maneuver {
+        start_orbit: first_orbit,
+        target_orbit: second_orbit.clone(),
+        execution_time: second_execution_time,
+        parent_change: None,
+    };
+    let maneuver_3 = Maneuver {
+        start_orbit: second_orbit,
+        target_orbit: third_orbit,
+        execution_time: third_execution_time,
+        parent_change: None,
+    };
+
+    Some(Transfer {
+        maneuvers: vec![maneuver_1, maneuver_2, maneuver_3].into(),
+    })
+}
+
+// Picks whichever of a Hohmann or bi-elliptic transfer needs the least delta-v, falling back to
+// the Hohmann transfer if no bi-elliptic transfer reaches the intermediate apoapsis.
+pub fn calculate_cheapest_transfer(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    intermediate_apoapsis: Scalar,
+    gravitational_parameter: Scalar,
+    execution_time: Scalar,
+) -> Result<Transfer, TransferError> {
+    let hohmann_transfer =
+        calculate_hohmann_transfer(start_orbit, target_orbit, gravitational_parameter, execution_time)?;
+    let bi_elliptic_transfer = calculate_bi_elliptic_transfer(
+        start_orbit,
+        target_orbit,
+        intermediate_apoapsis,
+        gravitational_parameter,
+        execution_time,
+    );
+
+    cheapest_transfer(
+        std::iter::once(hohmann_transfer).chain(bi_elliptic_transfer.ok()),
+        gravitational_parameter,
+    )
+}
+
+// The transfer needing the least total delta-v, ignoring any without a finite delta-v.
+pub(crate) fn cheapest_transfer(
+    transfers: impl IntoIterator<Item = Transfer>,
+    gravitational_parameter: Scalar,
+) -> Result<Transfer, TransferError> {
+    transfers
+        .into_iter()
+        .map(|transfer| (transfer.total_delta_v(gravitational_parameter), transfer))
+        .filter(|(delta_v, _)| delta_v.is_finite())
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, transfer)| transfer)
+        .ok_or(TransferError::NoSolution)
+}
+
+// The orbit left by a burn that sets the speed at the given time, leaving the velocity horizontal.
+pub(crate) fn calculate_tangential_burn(
+    orbit: &Orbit,
+    speed: Scalar,
+    gravitational_parameter: Scalar,
+    time: Scalar,
+) -> Orbit {
+    let (position, velocity) = orbit.state_at(time, gravitational_parameter);
+    let horizontal = position.cross(velocity).cross(position).normalize();
+    Orbit::from_state_vectors(position, horizontal * speed, gravitational_parameter, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::OrbitPlugin;
+
+    const GRAVITATIONAL_PARAMETER: Scalar = 1000.0;
+
+    // Relative to the distance from the parent at each burn.
+    const TOLERANCE: Scalar = 1e3 * Scalar::EPSILON;
+
+    fn orbit(semi_major_axis: Scalar, eccentricity: Scalar, inclination: Scalar) -> Orbit {
+        Orbit {
+            semi_major_axis,
+            eccentricity,
+            argument_of_periapsis: 0.5,
+            inclination,
+            longitude_of_ascending_node: 1.0,
+            initial_mean_anomaly: 2.0,
+        }
+    }
+
+    // Each burn starts from where the body is, changes only its velocity, and the last one leaves it
+    // on the target orbit.
+    fn assert_reaches_target(transfer: &Transfer, start_orbit: &Orbit, target_orbit: &Orbit) {
+        let mut orbit = start_orbit;
+        for maneuver in &transfer.maneuvers {
+            assert_eq!(&maneuver.start_orbit, orbit);
+
+            let time = maneuver.execution_time;
+            let (start_position, _) = maneuver.start_orbit.state_at(time, GRAVITATIONAL_PARAMETER);
+            let (target_position, _) = maneuver.target_orbit.state_at(time, GRAVITATIONAL_PARAMETER);
+            assert!(
+                (target_position - start_position).length() <= TOLERANCE * start_position.length(),
+                "burn at {time} moves the body from {start_position} to {target_position}"
+            );
+
+            orbit = &maneuver.target_orbit;
+        }
+
+        let arrival_orbit = Orbit {
+            initial_mean_anomaly: target_orbit.initial_mean_anomaly,
+            ..orbit.clone()
+        };
+        assert_eq!(&arrival_orbit, target_orbit);
+    }
+
+    #[test]
+    fn hohmann_transfers_reach_the_target() {
+        let cases = [
+            // circular
+            (orbit(10.0, 0.0, 0.0), orbit(40.0, 0.0, 0.0)),
+            (orbit(40.0, 0.0, 0.0), orbit(10.0, 0.0, 0.0)),
+            // elliptic
+            (orbit(10.0, 0.2, 0.0), orbit(40.0, 0.0, 0.0)),
+            (orbit(10.0, 0.0, 0.0), orbit(40.0, 0.5, 0.0)),
+            (orbit(10.0, 0.2, 0.0), orbit(40.0, 0.5, 0.0)),
+            // non-coplanar
+            (orbit(10.0, 0.0, 0.0), orbit(40.0, 0.0, 0.3)),
+            (orbit(10.0, 0.1, 0.2), orbit(40.0, 0.3, 1.0)),
+        ];
+        for (start_orbit, target_orbit) in cases {
+            let transfer =
+                calculate_hohmann_transfer(&start_orbit, &target_orbit, GRAVITATIONAL_PARAMETER, 5.0).unwrap();
+            assert_eq!(transfer.maneuvers.len(), 2);
+            assert!(transfer.maneuvers[0].execution_time >= 5.0);
+            assert_reaches_target(&transfer, &start_orbit, &target_orbit);
+        }
+    }
+
+    #[test]
+    fn hohmann_transfers_need_closed_orbits() {
+        let start_orbit = orbit(10.0, 0.0, 0.0);
+        let target_orbit = orbit(-40.0, 1.5, 0.0);
+        assert_eq!(
+            calculate_hohmann_transfer(&start_orbit, &target_orbit, GRAVITATIONAL_PARAMETER, 0.0).err(),
+            Some(TransferError::OpenOrbit)
+        );
+    }
+
+    #[test]
+    fn bi_elliptic_transfers_reach_the_target() {
+        let start_orbit = orbit(10.0, 0.0, 0.0);
+        for target_orbit in [orbit(200.0, 0.0, 0.0), orbit(150.0, 0.3, 0.0), orbit(200.0, 0.1, 0.4)] {
+            let transfer =
+                calculate_bi_elliptic_transfer(&start_orbit, &target_orbit, 400.0, GRAVITATIONAL_PARAMETER, 5.0)
+                    .unwrap();
+            assert_eq!(transfer.maneuvers.len(), 3);
+            assert_reaches_target(&transfer, &start_orbit, &target_orbit);
+        }
+    }
+
+    #[test]
+    fn bi_elliptic_transfers_beat_hohmann_transfers_between_distant_orbits() {
+        let start_orbit = orbit(10.0, 0.0, 0.0);
+        let target_orbit = orbit(200.0, 0.0, 0.0);
+
+        let hohmann_transfer =
+            calculate_hohmann_transfer(&start_orbit, &target_orbit, GRAVITATIONAL_PARAMETER, 0.0).unwrap();
+        let bi_elliptic_transfer =
+            calculate_bi_elliptic_transfer(&start_orbit, &target_orbit, 2000.0, GRAVITATIONAL_PARAMETER, 0.0).unwrap();
+        assert!(
+            bi_elliptic_transfer.total_delta_v(GRAVITATIONAL_PARAMETER)
+                < hohmann_transfer.total_delta_v(GRAVITATIONAL_PARAMETER)
+        );
+
+        let cheapest_transfer =
+            calculate_cheapest_transfer(&start_orbit, &target_orbit, 2000.0, GRAVITATIONAL_PARAMETER, 0.0).unwrap();
+        assert_eq!(cheapest_transfer.maneuvers.len(), 3);
+    }
+
+    #[test]
+    fn bi_elliptic_transfers_need_an_apoapsis_beyond_both_orbits() {
+        let start_orbit = orbit(10.0, 0.0, 0.0);
+        let target_orbit = orbit(200.0, 0.0, 0.0);
+        assert_eq!(
+            calculate_bi_elliptic_transfer(&start_orbit, &target_orbit, 100.0, GRAVITATIONAL_PARAMETER, 0.0).err(),
+            Some(TransferError::NoSolution)
+        );
+    }
+
+    fn count_events<E: Event>(app: &mut App) -> usize {
+        app.world_mut().resource_mut::<Events<E>>().drain().count()
+    }
+
+    #[test]
+    fn maneuvers_are_caught_up_and_undone() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin, OrbitPlugin::default()));
+        app.world_mut().resource_mut::<OrbitalTime>().paused = true;
+
+        let start_orbit = orbit(10.0, 0.0, 0.0);
+        let transfer =
+            calculate_hohmann_transfer(&start_orbit, &orbit(40.0, 0.0, 0.0), GRAVITATIONAL_PARAMETER, 5.0).unwrap();
+        let maneuvers = transfer.maneuvers.clone();
+        let mut schedule = TransferSchedule::default();
+        schedule.push_transfer(transfer);
+
+        let parent = app
+            .world_mut()
+            .spawn((
+                Mass::GravitationalParameter(GRAVITATIONAL_PARAMETER),
+                TransformBundle::default(),
+            ))
+            .id();
+        let entity =
+            app.world_mut().spawn((start_orbit.clone(), schedule, TransformBundle::default())).set_parent(parent).id();
+        app.update();
+
+        let jump_to = |app: &mut App, seconds: Scalar| {
+            app.world_mut().resource_mut::<OrbitalTime>().set_seconds(seconds);
+            app.update();
+            let orbit = app.world().get::<Orbit>(entity).unwrap().clone();
+            let schedule = app.world().get::<TransferSchedule>(entity).unwrap().clone();
+            (orbit, schedule)
+        };
+
+        // past both burns in one frame
+        let (orbit, schedule) = jump_to(&mut app, maneuvers[1].execution_time + 1.0);
+        assert_eq!(orbit, maneuvers[1].target_orbit);
+        assert_eq!(schedule.history.len(), 2);
+        assert!(schedule.transfers.is_empty());
+        assert_eq!(count_events::<ManeuverExecuted>(&mut app), 2);
+        assert_eq!(count_events::<TransferStarted>(&mut app), 1);
+        assert_eq!(count_events::<TransferCompleted>(&mut app), 1);
+
+        // back between the burns
+        let (orbit, schedule) = jump_to(&mut app, maneuvers[1].execution_time - 1.0);
+        assert_eq!(orbit, maneuvers[0].target_orbit);
+        assert_eq!(schedule.history.len(), 1);
+        assert_eq!(schedule.transfers.len(), 1);
+        assert_eq!(schedule.transfers[0].maneuvers.len(), 1);
+
+        // back before them, with the transfer rebuilt
+        let (orbit, schedule) = jump_to(&mut app, maneuvers[0].execution_time - 1.0);
+        assert_eq!(orbit, start_orbit);
+        assert!(schedule.history.is_empty());
+        assert_eq!(schedule.transfers.len(), 1);
+        let rebuilt = &schedule.transfers[0].maneuvers;
+        assert_eq!(rebuilt.len(), 2);
+        for (rebuilt, maneuver) in rebuilt.iter().zip(&maneuvers) {
+            assert_eq!(rebuilt.execution_time, maneuver.execution_time);
+            assert_eq!(rebuilt.target_orbit, maneuver.target_orbit);
+        }
+        assert_eq!(count_events::<ManeuverExecuted>(&mut app), 0);
+    }
+}
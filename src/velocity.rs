@@ -0,0 +1,65 @@
+use bevy::prelude::*;
+
+use crate::math::{calculate_velocity_at_time, rotate, Vector};
+use crate::orbit::{GravitationalConstant, Mass, Orbit};
+use crate::time::OrbitalTime;
+
+// Add to an orbiting entity to have its velocity calculated each frame. Velocities are in the
+// units of `Orbit::semi_major_axis` per second, unaffected by `OrbitScale` or `Transform` scale.
+#[derive(Component, Clone, Debug, Default)]
+pub struct OrbitalVelocity {
+    // Relative to the parent, in the parent's local space.
+    pub local: Vector,
+    // Including the motion of every orbiting ancestor, rotated into world space.
+    pub world: Vector,
+}
+
+pub fn calculate_orbital_velocities(
+    time: Res<OrbitalTime>,
+    gravitational_constant: Res<GravitationalConstant>,
+    masses: Query<&Mass>,
+    orbits: Query<(&Orbit, &Parent)>,
+    transforms: Query<&GlobalTransform>,
+    mut velocities: Query<(Entity, &mut OrbitalVelocity)>,
+) {
+    let seconds = time.seconds();
+    for (entity, mut velocity) in velocities.iter_mut() {
+        let mut local = Vector::ZERO;
+        let mut world = Vector::ZERO;
+
+        let mut current = entity;
+        while let Ok((orbit, parent)) = orbits.get(current) {
+            let Ok(parent_mass) = masses.get(parent.get()) else {
+                break;
+            };
+
+            let relative_velocity = if orbit.semi_major_axis == 0.0 {
+                Vector::ZERO
+            } else {
+                Vector::from(calculate_velocity_at_time(
+                    orbit.semi_major_axis,
+                    orbit.eccentricity,
+                    orbit.argument_of_periapsis,
+                    orbit.inclination,
+                    orbit.longitude_of_ascending_node,
+                    orbit.initial_mean_anomaly,
+                    parent_mass.gravitational_parameter(&gravitational_constant),
+                    seconds,
+                ))
+            };
+
+            if current == entity {
+                local = relative_velocity;
+            }
+
+            let parent_transform = transforms.get(parent.get()).copied().unwrap_or_default();
+            let (_, parent_rotation, _) = parent_transform.to_scale_rotation_translation();
+            world += rotate(parent_rotation, relative_velocity);
+
+            current = parent.get();
+        }
+
+        velocity.local = local;
+        velocity.world = world;
+    }
+}
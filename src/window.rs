@@ -0,0 +1,159 @@
+use std::ops::RangeInclusive;
+
+use crate::math::*;
+use crate::orbit::Orbit;
+use crate::transfer::{calculate_lambert_transfer, Transfer, TransferError};
+
+// Number of departure times, and of flight times, tried when searching for a transfer window.
+const SEARCH_STEPS: usize = 32;
+
+// A Lambert transfer between two bodies, and the total delta-v of its departure and arrival burns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferWindow {
+    pub departure_time: Scalar,
+    pub time_of_flight: Scalar,
+    pub delta_v: Scalar,
+}
+
+impl TransferWindow {
+    pub fn arrival_time(&self) -> Scalar {
+        self.departure_time + self.time_of_flight
+    }
+
+    // The transfer that departs and arrives at the times of this window.
+    pub fn transfer(
+        &self,
+        start_orbit: &Orbit,
+        target_orbit: &Orbit,
+        gravitational_parameter: Scalar,
+    ) -> Result<Transfer, TransferError> {
+        calculate_lambert_transfer(
+            start_orbit,
+            target_orbit,
+            gravitational_parameter,
+            self.departure_time,
+            self.arrival_time(),
+        )
+    }
+}
+
+// Delta-v of Lambert transfers over a grid of departure times and times of flight, the data
+// behind a porkchop plot.
+#[derive(Debug, Clone)]
+pub struct Porkchop {
+    pub departure_times: Vec<Scalar>,
+    pub times_of_flight: Vec<Scalar>,
+    // Indexed by departure time then time of flight, and infinite where there's no transfer.
+    pub delta_v: Vec<Vec<Scalar>>,
+    pub optimum: Option<TransferWindow>,
+}
+
+// Evenly samples both ranges, including their ends, and calculates the delta-v of a transfer
+// from the body on the start orbit to the body on the target orbit for every combination.
+pub fn calculate_porkchop(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    departure_times: RangeInclusive<Scalar>,
+    times_of_flight: RangeInclusive<Scalar>,
+    departure_steps: usize,
+    flight_steps: usize,
+) -> Porkchop {
+    let departure_times = sample_range(departure_times, departure_steps);
+    let times_of_flight = sample_range(times_of_flight, flight_steps);
+
+    let mut optimum: Option<TransferWindow> = None;
+    let delta_v = departure_times
+        .iter()
+        .map(|&departure_time| {
+            times_of_flight
+                .iter()
+                .map(|&time_of_flight| {
+                    let delta_v = calculate_lambert_delta_v(
+                        start_orbit,
+                        target_orbit,
+                        gravitational_parameter,
+                        departure_time,
+                        time_of_flight,
+                    );
+                    if delta_v.is_finite() && optimum.as_ref().map_or(true, |optimum| delta_v < optimum.delta_v) {
+                        optimum = Some(TransferWindow {
+                            departure_time,
+                            time_of_flight,
+                            delta_v,
+                        });
+                    }
+                    delta_v
+                })
+                .collect()
+        })
+        .collect();
+
+    Porkchop {
+        departure_times,
+        times_of_flight,
+        delta_v,
+        optimum,
+    }
+}
+
+// The cheapest transfer window departing within one synodic period of `earliest_departure` (or
+// eight periods of the start orbit, if that's shorter), with times of flight from half to one
+// and a half times that of a Hohmann transfer between the orbits.
+pub fn calculate_transfer_window(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    earliest_departure: Scalar,
+) -> Result<TransferWindow, TransferError> {
+    if start_orbit.conic() != Conic::Ellipse || target_orbit.conic() != Conic::Ellipse {
+        return Err(TransferError::OpenOrbit);
+    }
+
+    let start_period = calculate_period(start_orbit.semi_major_axis, gravitational_parameter);
+    let target_period = calculate_period(target_orbit.semi_major_axis, gravitational_parameter);
+    let synodic_period = calculate_synodic_period(start_period, target_period);
+    let departure_window = synodic_period.min(8.0 * start_period);
+
+    let transfer_semi_major_axis = (start_orbit.semi_major_axis + target_orbit.semi_major_axis) / 2.0;
+    let hohmann_time = calculate_period(transfer_semi_major_axis, gravitational_parameter) / 2.0;
+
+    calculate_porkchop(
+        start_orbit,
+        target_orbit,
+        gravitational_parameter,
+        earliest_departure..=earliest_departure + departure_window,
+        0.5 * hohmann_time..=1.5 * hohmann_time,
+        SEARCH_STEPS,
+        SEARCH_STEPS,
+    )
+    .optimum
+    .ok_or(TransferError::NoSolution)
+}
+
+// Total delta-v of leaving the start orbit at `departure_time` and matching the body on the
+// target orbit `time_of_flight` later, without building the transfer's orbits.
+fn calculate_lambert_delta_v(
+    start_orbit: &Orbit,
+    target_orbit: &Orbit,
+    gravitational_parameter: Scalar,
+    departure_time: Scalar,
+    time_of_flight: Scalar,
+) -> Scalar {
+    let (departure_position, start_velocity) = start_orbit.state_at(departure_time, gravitational_parameter);
+    let (arrival_position, target_velocity) =
+        target_orbit.state_at(departure_time + time_of_flight, gravitational_parameter);
+
+    match calculate_lambert_velocities(
+        departure_position,
+        arrival_position,
+        time_of_flight,
+        departure_position.cross(start_velocity),
+        gravitational_parameter,
+    ) {
+        Some((departure_velocity, arrival_velocity)) => {
+            (departure_velocity - start_velocity).length() + (target_velocity - arrival_velocity).length()
+        }
+        None => Scalar::INFINITY,
+    }
+}